@@ -0,0 +1,559 @@
+use deadline::Deadline;
+use error::{Error, DeadlineExceeded, NetworkError};
+use liveness::{Liveness, Peer};
+use reactor::{Reactor, TimerHandle};
+use std::cell::RefCell;
+use std::collections::{HashMap, RingBuf};
+use std::default::Default;
+use std::mem;
+use wire::{mod, Cancel, Credit, Envelope, ErrorReply, Frames, Heartbeat, Ready,
+           Reply, Request, StreamEnd, StreamItem, StreamRequest};
+use zmq;
+
+struct Worker {
+    identity: Vec<u8>,
+    outstanding: uint,
+    peer: Peer,
+}
+
+struct Waiting {
+    route: Frames,
+    deadline: Option<Deadline>,
+    request: Envelope,
+}
+
+/// Load-balancing proxy between clients, connected to a frontend ROUTER, and
+/// workers, whose `server::Endpoint`-s use DEALER sockets connected to a
+/// backend ROUTER and announce themselves with `Endpoint::send_ready`.
+///
+/// Each request goes to the worker with the fewest outstanding requests.
+/// Requests arriving while no worker is ready wait in the broker, with their
+/// deadline still running; see `register_expiry`. Requests streamed by the
+/// client follow their call to its worker, waiting along with it if need be,
+/// and the credits granted for a stream go wherever its messages come from.
+///
+/// The broker sends heartbeats to its workers, see `heartbeat`, and echoes
+/// the ones sent by clients.
+pub struct Broker {
+    frontend: zmq::Socket,
+    backend: zmq::Socket,
+    workers: Vec<Worker>,
+    waiting: RingBuf<Waiting>,
+    // Worker handling each request, by client route and request id.
+    assigned: HashMap<(Frames, u64), Vec<u8>>,
+    // Requests streamed for calls still waiting, by client route and id.
+    following: HashMap<(Frames, u64), Vec<Envelope>>,
+    liveness: Liveness,
+}
+
+impl Broker {
+    pub fn new(frontend: zmq::Socket, backend: zmq::Socket) -> Broker {
+        Broker {
+            frontend: frontend,
+            backend: backend,
+            workers: Vec::new(),
+            waiting: RingBuf::new(),
+            assigned: HashMap::new(),
+            following: HashMap::new(),
+            liveness: Default::default(),
+        }
+    }
+
+    /// Sets how often workers are sent heartbeats and how long they may stay
+    /// silent, which defaults to `Liveness::default()`.
+    pub fn set_liveness(&mut self, liveness: Liveness) {
+        for worker in self.workers.iter_mut() {
+            worker.peer = Peer::new(liveness.clone());
+        }
+        self.liveness = liveness;
+    }
+
+    pub fn liveness(&self) -> &Liveness { &self.liveness }
+
+    pub fn frontend_poll_item<'b>(&self, events: i16) -> zmq::PollItem<'b> {
+        self.frontend.as_poll_item(events)
+    }
+
+    pub fn backend_poll_item<'b>(&self, events: i16) -> zmq::PollItem<'b> {
+        self.backend.as_poll_item(events)
+    }
+
+    /// The number of workers which announced themselves.
+    pub fn workers(&self) -> uint { self.workers.len() }
+
+    /// The number of requests waiting for a worker.
+    pub fn waiting(&self) -> uint { self.waiting.len() }
+
+    /// The earliest deadline among requests waiting for a worker.
+    pub fn next_expiry(&self) -> Option<Deadline> {
+        self.waiting.iter().fold(None, |earliest, waiting| {
+            Deadline::earliest(earliest, waiting.deadline)
+        })
+    }
+
+    /// Fails every waiting request whose deadline has passed with
+    /// `DeadlineExceeded`.
+    pub fn expire(&mut self) -> Result<(), Error> {
+        let waiting = mem::replace(&mut self.waiting, RingBuf::new());
+        let mut expired = Vec::new();
+        for waiting in waiting.into_iter() {
+            if waiting.deadline.map_or(false, |d| d.expired()) {
+                expired.push(waiting);
+            } else {
+                self.waiting.push_back(waiting);
+            }
+        }
+        for Waiting { route, request, .. } in expired.into_iter() {
+            try!(self.deadline_exceeded(route, request.id));
+        }
+        Ok(())
+    }
+
+    /// Has `reactor` call `expire` on `broker` as soon as the earliest
+    /// deadline among its waiting requests passes, so that requests don't
+    /// outlive their deadline while no worker is ready.
+    pub fn register_expiry<'a, 'b>(broker: &'a RefCell<Broker>,
+                                   reactor: &mut Reactor<'a, 'b>)
+            -> TimerHandle {
+        reactor.schedule_dynamic(
+            box |&:| broker.borrow().next_expiry(),
+            box |&mut:| {
+                match broker.borrow_mut().expire() {
+                    Ok(()) => {},
+                    Err(err) => error!("broker: expire: {}", err),
+                }
+            })
+    }
+
+    /// Sends a heartbeat to every worker. Schedule it with
+    /// `Reactor::schedule_repeating` every `Liveness::interval`.
+    ///
+    /// Workers not heard from within `Liveness::timeout` are dropped, and the
+    /// requests they were handling fail with `NetworkError`. A dropped worker
+    /// comes back by announcing itself again, see `Endpoint::heartbeat`.
+    pub fn heartbeat(&mut self) -> Result<(), Error> {
+        let workers = mem::replace(&mut self.workers, Vec::new());
+        let mut dead = Vec::new();
+        for mut worker in workers.into_iter() {
+            if worker.peer.check() {
+                warn!("broker: worker {} stopped responding to heartbeats",
+                      worker.identity);
+                dead.push(worker.identity);
+            } else {
+                self.workers.push(worker);
+            }
+        }
+        for identity in dead.iter() {
+            try!(self.fail_assigned(identity.as_slice()));
+        }
+        for worker in self.workers.iter() {
+            let frames =
+                Envelope::heartbeat().encode(vec![worker.identity.clone()]);
+            try!(wire::send_frames(&mut self.backend, frames.as_slice()));
+        }
+        Ok(())
+    }
+
+    /// Receives a single message from a client, forwarding requests to a
+    /// worker, and cancellations, streamed requests and credits to the worker
+    /// handling the call.
+    pub fn process_frontend(&mut self) -> Result<(), Error> {
+        let frames = try!(wire::recv_frames(&mut self.frontend, 0));
+        let (route, request) = try!(Envelope::decode(frames));
+        match request.kind {
+            Request | StreamRequest => {
+                if request.kind == StreamRequest {
+                    self.following.insert((route.clone(), request.id),
+                                          Vec::new());
+                }
+                self.waiting.push_back(Waiting {
+                    route: route,
+                    deadline: request.budget.map(Deadline::after),
+                    request: request,
+                });
+                self.dispatch_waiting()
+            },
+            Cancel => {
+                let id = request.id;
+                // Workers don't reply to cancelled requests, so the request
+                // stops counting towards the worker's load right away.
+                let key = (route.clone(), id);
+                let identity = match self.assigned.remove(&key) {
+                    Some(identity) => {
+                        self.finished(identity.as_slice());
+                        identity
+                    },
+                    None => {
+                        // Either still waiting for a worker, or done.
+                        self.following.remove(&key);
+                        let waiting = mem::replace(&mut self.waiting,
+                                                   RingBuf::new());
+                        self.waiting = waiting.into_iter().filter(|waiting| {
+                            waiting.route != route || waiting.request.id != id
+                        }).collect();
+                        return Ok(());
+                    },
+                };
+                self.to_worker(identity, &route, request)
+            },
+            StreamItem | StreamEnd | Credit => {
+                let key = (route.clone(), request.id);
+                match self.assigned.get(&key).map(|identity| identity.clone()) {
+                    Some(identity) => {
+                        return self.to_worker(identity, &route, request);
+                    },
+                    None => {},
+                }
+                // Dropped if the call is already over.
+                match self.following.get_mut(&key) {
+                    Some(following) => following.push(request),
+                    None => {},
+                }
+                Ok(())
+            },
+            Heartbeat => {
+                let frames = request.encode(route);
+                wire::send_frames(&mut self.frontend, frames.as_slice())
+            },
+            _ => Err(Error::with_desc(NetworkError, "expected request")),
+        }
+    }
+
+    /// Receives a single message from a worker: a reply, a message of a
+    /// stream or a credit, which is forwarded to the client, a ready
+    /// announcement or a heartbeat. Workers remain assigned streams until
+    /// their end.
+    pub fn process_backend(&mut self) -> Result<(), Error> {
+        let mut frames = try!(wire::recv_frames(&mut self.backend, 0));
+        if frames.is_empty() {
+            return Err(Error::with_desc(NetworkError, "missing identity"));
+        }
+        let identity = frames.remove(0).unwrap();
+        match self.find(identity.as_slice()) {
+            Some(index) => { self.workers[mut][index].peer.seen(); },
+            None => {},
+        }
+        let (route, reply) = try!(Envelope::decode(frames));
+        match reply.kind {
+            Ready => {
+                if self.find(identity.as_slice()).is_none() {
+                    info!("broker: worker {} ready", identity);
+                    self.workers.push(Worker {
+                        identity: identity,
+                        outstanding: 0,
+                        peer: Peer::new(self.liveness.clone()),
+                    });
+                }
+                self.dispatch_waiting()
+            },
+            Heartbeat => Ok(()),
+            StreamItem | Credit => {
+                let frames = reply.encode(route);
+                wire::send_frames(&mut self.frontend, frames.as_slice())
+            },
+            Reply | ErrorReply | StreamEnd => {
+                if self.assigned.remove(&(route.clone(), reply.id)).is_some() {
+                    self.finished(identity.as_slice());
+                }
+                let frames = reply.encode(route);
+                wire::send_frames(&mut self.frontend, frames.as_slice())
+            },
+            _ => Err(Error::with_desc(NetworkError, "expected reply")),
+        }
+    }
+
+    fn find(&self, identity: &[u8]) -> Option<uint> {
+        self.workers.iter()
+            .position(|worker| worker.identity.as_slice() == identity)
+    }
+
+    fn finished(&mut self, identity: &[u8]) {
+        match self.find(identity) {
+            Some(index) => self.workers[mut][index].outstanding -= 1,
+            None => {},
+        }
+    }
+
+    /// Fails the requests assigned to a dead worker.
+    fn fail_assigned(&mut self, identity: &[u8]) -> Result<(), Error> {
+        let keys: Vec<(Frames, u64)> = self.assigned.iter()
+            .filter(|&(_, assigned)| assigned.as_slice() == identity)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys.into_iter() {
+            self.assigned.remove(&key);
+            let (route, id) = key;
+            let reply = Envelope::reply(id, Err(Error::with_desc(
+                NetworkError, "worker stopped responding to heartbeats")));
+            let frames = reply.encode(route);
+            try!(wire::send_frames(&mut self.frontend, frames.as_slice()));
+        }
+        Ok(())
+    }
+
+    /// Sends waiting requests to the least busy workers, failing the ones
+    /// whose deadline passed in the meantime.
+    fn dispatch_waiting(&mut self) -> Result<(), Error> {
+        while !self.workers.is_empty() {
+            let Waiting { route, deadline, mut request } =
+                match self.waiting.pop_front() {
+                    Some(waiting) => waiting,
+                    None => break,
+                };
+            if deadline.map_or(false, |d| d.expired()) {
+                try!(self.deadline_exceeded(route, request.id));
+                continue;
+            }
+            request.budget = deadline.map(|d| d.remaining());
+
+            let (index, _) = self.workers.iter().enumerate()
+                .min_by(|&(_, worker)| worker.outstanding)
+                .unwrap();
+            let identity = self.workers[index].identity.clone();
+            self.workers[mut][index].outstanding += 1;
+            let key = (route.clone(), request.id);
+            self.assigned.insert(key.clone(), identity.clone());
+
+            try!(self.to_worker(identity.clone(), &route, request));
+            match self.following.remove(&key) {
+                Some(following) => {
+                    for request in following.into_iter() {
+                        try!(self.to_worker(identity.clone(), &route,
+                                            request));
+                    }
+                },
+                None => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn to_worker(&mut self, identity: Vec<u8>, route: &Frames,
+                 envelope: Envelope) -> Result<(), Error> {
+        let mut frames = vec![identity];
+        frames.push_all(route.as_slice());
+        let frames = envelope.encode(frames);
+        wire::send_frames(&mut self.backend, frames.as_slice())
+    }
+
+    fn deadline_exceeded(&mut self, route: Frames, id: u64)
+            -> Result<(), Error> {
+        self.following.remove(&(route.clone(), id));
+        let reply = Envelope::reply(id, Err(Error::with_desc(
+            DeadlineExceeded, "deadline exceeded in broker")));
+        let frames = reply.encode(route);
+        wire::send_frames(&mut self.frontend, frames.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Broker;
+    use client::Channel;
+    use codec::{mod, JsonCodec};
+    use deadline::Deadline;
+    use error::{Error, DeadlineExceeded, NetworkError};
+    use executor::{Inline, ThreadPool};
+    use future::AsyncFuture;
+    use liveness::Liveness;
+    use reactor::Reactor;
+    use server::{Context, Dispatch, Endpoint, Requests};
+    use std::cell::RefCell;
+    use std::io::timer;
+    use std::time::Duration;
+    use zmq;
+
+    struct Named(&'static str);
+    impl Dispatch for Named {
+        fn dispatch(&mut self, _ctx: &Context, _method: &str, _payload: &[u8])
+                -> Result<Vec<u8>, Error> {
+            let Named(name) = *self;
+            Ok(format!("\"{}\"", name).into_bytes())
+        }
+    }
+
+    #[deriving(Clone)]
+    struct Summing;
+    impl Dispatch for Summing {
+        fn dispatch(&mut self, _ctx: &Context, _method: &str, _payload: &[u8])
+                -> Result<Vec<u8>, Error> {
+            unreachable!()
+        }
+
+        fn takes_stream(&self, _method: &str) -> bool { true }
+
+        fn dispatch_client_stream(&mut self, _ctx: &Context, _method: &str,
+                                  requests: &mut Requests)
+                -> Result<Vec<u8>, Error> {
+            let mut sum = 0i;
+            loop {
+                let request = match requests.recv() {
+                    Some(request) => try!(request),
+                    None => break,
+                };
+                let n: int =
+                    try!(codec::decode(&JsonCodec, request.as_slice()));
+                sum += n;
+            }
+            codec::encode(&JsonCodec, &sum)
+        }
+    }
+
+    fn worker(ctx: &mut zmq::Context, backend: &str, name: &'static str)
+            -> Endpoint<Inline<Named>> {
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect(backend).unwrap();
+        let mut endpoint = Endpoint::new(socket, Named(name));
+        endpoint.send_ready().unwrap();
+        endpoint
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let mut ctx = zmq::Context::new();
+        let mut frontend = ctx.socket(zmq::ROUTER).unwrap();
+        frontend.bind("inproc://zuffy-broker-frontend").unwrap();
+        let mut backend = ctx.socket(zmq::ROUTER).unwrap();
+        backend.bind("inproc://zuffy-broker-backend").unwrap();
+        let mut broker = Broker::new(frontend, backend);
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-broker-frontend").unwrap();
+        let mut channel = Channel::new(socket, JsonCodec);
+
+        // Requests wait in the broker until a worker is ready.
+        let first: AsyncFuture<Result<String, Error>> =
+            channel.call("name", &()).async();
+        broker.process_frontend().unwrap();
+        assert_eq!(broker.waiting(), 1);
+
+        let mut a = worker(&mut ctx, "inproc://zuffy-broker-backend", "a");
+        broker.process_backend().unwrap();
+        assert_eq!(broker.workers(), 1);
+        assert_eq!(broker.waiting(), 0);
+        let mut b = worker(&mut ctx, "inproc://zuffy-broker-backend", "b");
+        broker.process_backend().unwrap();
+        assert_eq!(broker.workers(), 2);
+
+        // `a` is still busy with the first request.
+        let second: AsyncFuture<Result<String, Error>> =
+            channel.call("name", &()).async();
+        broker.process_frontend().unwrap();
+
+        b.process().unwrap();
+        broker.process_backend().unwrap();
+        channel.process().unwrap();
+        assert!(second.ready());
+        assert!(!first.ready());
+
+        a.process().unwrap();
+        broker.process_backend().unwrap();
+        channel.process().unwrap();
+        first.map(proc(name) assert_eq!(name.ok().unwrap().as_slice(), "a"));
+        second.map(proc(name) assert_eq!(name.ok().unwrap().as_slice(), "b"));
+    }
+
+    #[test]
+    fn test_waiting_expiry() {
+        let mut ctx = zmq::Context::new();
+        let mut frontend = ctx.socket(zmq::ROUTER).unwrap();
+        frontend.bind("inproc://zuffy-broker-expiry-frontend").unwrap();
+        let backend = ctx.socket(zmq::ROUTER).unwrap();
+        let broker = RefCell::new(Broker::new(frontend, backend));
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-broker-expiry-frontend").unwrap();
+        let mut channel = Channel::new(socket, JsonCodec);
+        let reply: AsyncFuture<Result<String, Error>> = channel
+            .deadline(Deadline::after(Duration::milliseconds(5)))
+            .call("name", &())
+            .async();
+        broker.borrow_mut().process_frontend().unwrap();
+        assert_eq!(broker.borrow().waiting(), 1);
+
+        // No worker ever shows up, and the broker fails the request anyway.
+        let mut reactor = Reactor::new();
+        Broker::register_expiry(&broker, &mut reactor);
+        assert!(reactor.run_until(|| broker.borrow().waiting() == 0));
+        channel.process().unwrap();
+        reply.map(proc(reply) {
+            assert_eq!(reply.err().unwrap().code(), DeadlineExceeded);
+        });
+    }
+
+    #[test]
+    fn test_dead_worker() {
+        let mut ctx = zmq::Context::new();
+        let mut frontend = ctx.socket(zmq::ROUTER).unwrap();
+        frontend.bind("inproc://zuffy-broker-dead-frontend").unwrap();
+        let mut backend = ctx.socket(zmq::ROUTER).unwrap();
+        backend.bind("inproc://zuffy-broker-dead-backend").unwrap();
+        let mut broker = Broker::new(frontend, backend);
+        broker.set_liveness(Liveness::new(Duration::milliseconds(1), 2));
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-broker-dead-frontend").unwrap();
+        let mut channel = Channel::new(socket, JsonCodec);
+
+        let mut a = worker(&mut ctx, "inproc://zuffy-broker-dead-backend", "a");
+        a.set_liveness(Some(Liveness::new(Duration::milliseconds(1), 2)));
+        broker.process_backend().unwrap();
+        let reply: AsyncFuture<Result<String, Error>> =
+            channel.call("name", &()).async();
+        broker.process_frontend().unwrap();
+
+        // The worker is stuck, so it misses the broker's heartbeats.
+        timer::sleep(Duration::milliseconds(5));
+        broker.heartbeat().unwrap();
+        assert_eq!(broker.workers(), 0);
+        channel.process().unwrap();
+        reply.map(proc(reply) {
+            assert_eq!(reply.err().unwrap().code(), NetworkError);
+        });
+
+        // Once unstuck, it hasn't heard from the broker either and announces
+        // itself again.
+        a.heartbeat().unwrap();
+        broker.process_backend().unwrap();
+        assert_eq!(broker.workers(), 1);
+    }
+
+    #[test]
+    fn test_following() {
+        let mut ctx = zmq::Context::new();
+        let mut frontend = ctx.socket(zmq::ROUTER).unwrap();
+        frontend.bind("inproc://zuffy-broker-following-frontend").unwrap();
+        let mut backend = ctx.socket(zmq::ROUTER).unwrap();
+        backend.bind("inproc://zuffy-broker-following-backend").unwrap();
+        let mut broker = Broker::new(frontend, backend);
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-broker-following-frontend").unwrap();
+        let mut channel = Channel::new(socket, JsonCodec);
+        let (mut sink, sum) = channel.call_client_stream::<int, int>("sum");
+        sink.send(&1).unwrap();
+        sink.send(&2).unwrap();
+        sink.close();
+        channel.handle_events(zmq::POLLOUT).unwrap();
+        for _ in range(0u, 4) {
+            broker.process_frontend().unwrap();
+        }
+        assert_eq!(broker.waiting(), 1);
+
+        // The requests streamed meanwhile follow the call to its worker.
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-broker-following-backend").unwrap();
+        let pool = ThreadPool::new(&mut ctx, 1, Summing).unwrap();
+        let mut worker = Endpoint::with_executor(socket, pool);
+        worker.send_ready().unwrap();
+        broker.process_backend().unwrap();
+        assert_eq!(broker.waiting(), 0);
+        for _ in range(0u, 4) {
+            worker.process().unwrap();
+        }
+        worker.process_reply().unwrap();
+        broker.process_backend().unwrap();
+        channel.process().unwrap();
+        sum.map(proc(sum) assert_eq!(sum.ok().unwrap(), 3));
+    }
+}
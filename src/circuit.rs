@@ -0,0 +1,322 @@
+use deadline;
+use error::{ErrorCode, DeadlineExceeded, NetworkError, Unavailable};
+use std::collections::RingBuf;
+use std::default::Default;
+use std::i64;
+use std::num::Saturating;
+use std::time::Duration;
+
+/// The state of a `CircuitBreaker`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum CircuitState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail right away with `Unavailable`.
+    Open,
+    /// A few probe calls go through to check whether the endpoint recovered.
+    HalfOpen,
+}
+
+enum State {
+    Tracking,
+    // Until when, on the monotonic clock in nanoseconds.
+    Tripped(u64),
+    // When the probes admitted so far are given up on, how many were
+    // admitted and how many succeeded.
+    Probing(u64, uint, uint),
+}
+
+/// Fails calls to an endpoint fast once too many of them failed recently,
+/// see `Channel::set_circuit_breaker`.
+///
+/// The breaker opens when, among the last `window` calls, the share of calls
+/// failing with a tripping error code reaches `failure_threshold`. Other
+/// errors mean the endpoint is up and count as successes. After `open_for`
+/// it half-opens, letting through `probes` calls; it closes if they all
+/// succeed and opens again as soon as one fails. Cancelled probes make room
+/// for others, see `cancel_probe`.
+pub struct CircuitBreaker {
+    window: uint,
+    failure_threshold: f64,
+    min_calls: uint,
+    open_for: Duration,
+    probes: uint,
+    tripping: Vec<ErrorCode>,
+    state: State,
+    // The error code each recent call failed with, oldest first.
+    outcomes: RingBuf<Option<ErrorCode>>,
+    rejected: uint,
+}
+
+impl CircuitBreaker {
+    /// A breaker which trips on `NetworkError`, `DeadlineExceeded` and
+    /// `Unavailable`, once at least half of `window` calls were made.
+    /// `failure_threshold` must be in (0, 1].
+    pub fn new(window: uint, failure_threshold: f64, open_for: Duration)
+            -> CircuitBreaker {
+        assert!(window > 0, "The window must hold at least one call.");
+        assert!(failure_threshold > 0.0 && failure_threshold <= 1.0,
+                "The failure threshold must be in (0, 1].");
+        CircuitBreaker {
+            window: window,
+            failure_threshold: failure_threshold,
+            min_calls: (window + 1) / 2,
+            open_for: open_for,
+            probes: 1,
+            tripping: vec![NetworkError, DeadlineExceeded, Unavailable],
+            state: Tracking,
+            outcomes: RingBuf::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Sets how many calls must be in the window before the breaker opens.
+    pub fn set_min_calls(&mut self, min_calls: uint) {
+        self.min_calls = min_calls;
+    }
+
+    /// Sets how many probe calls go through while half-open.
+    pub fn set_probes(&mut self, probes: uint) {
+        assert!(probes > 0, "At least one probe must go through.");
+        self.probes = probes;
+    }
+
+    /// Sets the error codes which count as failures.
+    pub fn set_tripping(&mut self, codes: Vec<ErrorCode>) {
+        self.tripping = codes;
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state_at(deadline::now_ns())
+    }
+
+    /// The share of calls in the window which failed with a tripping error
+    /// code.
+    pub fn failure_rate(&self) -> f64 {
+        let tripping = &self.tripping;
+        self.rate(|code| tripping.contains(&code))
+    }
+
+    /// The share of calls in the window which failed with `code`, tripping
+    /// or not.
+    pub fn code_rate(&self, code: ErrorCode) -> f64 {
+        self.rate(|failed| failed == code)
+    }
+
+    /// The number of calls in the window which failed with `code`, tripping
+    /// or not.
+    pub fn failures(&self, code: ErrorCode) -> uint {
+        self.outcomes.iter().filter(|&&failure| failure == Some(code)).count()
+    }
+
+    /// The number of calls failed fast while open.
+    pub fn rejected(&self) -> uint { self.rejected }
+
+    /// Whether a call may go through. Each call allowed through must be
+    /// followed by a `record`, or by a `cancel_probe` if it was cancelled
+    /// while `probing`.
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(deadline::now_ns())
+    }
+
+    /// Whether calls allowed through now are probes.
+    pub fn probing(&self) -> bool {
+        match self.state {
+            Probing(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Gives up on a probe without an outcome, letting another call through
+    /// in its place.
+    pub fn cancel_probe(&mut self) {
+        match self.state {
+            Probing(until, admitted, succeeded) if admitted > 0 => {
+                self.state = Probing(until, admitted - 1, succeeded);
+            },
+            _ => {},
+        }
+    }
+
+    /// Records the outcome of a call, given the error code it failed with.
+    pub fn record(&mut self, failure: Option<ErrorCode>) {
+        self.record_at(failure, deadline::now_ns())
+    }
+
+    fn state_at(&self, now_ns: u64) -> CircuitState {
+        match self.state {
+            Tracking => Closed,
+            Tripped(until_ns) if now_ns >= until_ns => HalfOpen,
+            Tripped(_) => Open,
+            Probing(..) => HalfOpen,
+        }
+    }
+
+    fn rate(&self, counts: |ErrorCode| -> bool) -> f64 {
+        if self.outcomes.is_empty() { return 0.0; }
+        let mut failed = 0u;
+        for failure in self.outcomes.iter() {
+            match *failure {
+                Some(code) if counts(code) => failed += 1,
+                _ => {},
+            }
+        }
+        failed as f64 / self.outcomes.len() as f64
+    }
+
+    fn allow_at(&mut self, now_ns: u64) -> bool {
+        let allowed = match self.state {
+            Tracking => true,
+            Tripped(until_ns) | Probing(until_ns, _, _)
+                    if now_ns >= until_ns => {
+                self.state = Probing(self.open_until(now_ns), 1, 0);
+                true
+            },
+            Tripped(_) => false,
+            Probing(until_ns, admitted, succeeded) => {
+                if admitted < self.probes {
+                    self.state = Probing(until_ns, admitted + 1, succeeded);
+                    true
+                } else {
+                    false
+                }
+            },
+        };
+        if !allowed { self.rejected += 1; }
+        allowed
+    }
+
+    fn record_at(&mut self, failure: Option<ErrorCode>, now_ns: u64) {
+        let failed =
+            failure.map_or(false, |code| self.tripping.contains(&code));
+        match self.state {
+            Tracking => {
+                self.outcomes.push_back(failure);
+                if self.outcomes.len() > self.window {
+                    self.outcomes.pop_front();
+                }
+                if self.outcomes.len() >= self.min_calls &&
+                        self.failure_rate() >= self.failure_threshold {
+                    warn!("circuit: opening, failure rate {}",
+                          self.failure_rate());
+                    self.state = Tripped(self.open_until(now_ns));
+                }
+            },
+            // A call let through before the breaker opened.
+            Tripped(_) => {},
+            Probing(..) if failed => {
+                warn!("circuit: probe failed, opening again");
+                self.state = Tripped(self.open_until(now_ns));
+            },
+            Probing(until_ns, admitted, succeeded) => {
+                if succeeded + 1 >= self.probes {
+                    info!("circuit: probes succeeded, closing");
+                    self.state = Tracking;
+                    self.outcomes.clear();
+                } else {
+                    self.state = Probing(until_ns, admitted, succeeded + 1);
+                }
+            },
+        }
+    }
+
+    fn open_until(&self, now_ns: u64) -> u64 {
+        let open_for_ns = self.open_for.num_nanoseconds()
+            .unwrap_or(i64::MAX) as u64;
+        now_ns.saturating_add(open_for_ns)
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new(20, 0.5, Duration::seconds(5))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CircuitBreaker, Closed, HalfOpen, Open};
+    use error::{InternalServerError, NetworkError, Unavailable};
+    use std::time::Duration;
+
+    #[test]
+    fn test_trips_and_recovers() {
+        let mut breaker =
+            CircuitBreaker::new(4, 0.5, Duration::nanoseconds(50));
+        assert!(breaker.allow_at(100));
+        breaker.record_at(Some(NetworkError), 100);
+        // Too few calls to tell yet.
+        assert_eq!(breaker.state_at(100), Closed);
+        assert!(breaker.allow_at(110));
+        // Application errors don't count.
+        breaker.record_at(Some(InternalServerError), 110);
+        assert_eq!(breaker.state_at(110), Closed);
+        assert!(breaker.allow_at(120));
+        breaker.record_at(Some(NetworkError), 120);
+        assert_eq!(breaker.state_at(120), Open);
+        assert_eq!(breaker.failures(NetworkError), 2);
+        assert_eq!(breaker.code_rate(InternalServerError), 1.0 / 3.0);
+
+        assert!(!breaker.allow_at(130));
+        assert_eq!(breaker.rejected(), 1);
+
+        // A single probe goes through, and fails.
+        assert_eq!(breaker.state_at(170), HalfOpen);
+        assert!(breaker.allow_at(170));
+        assert!(!breaker.allow_at(170));
+        breaker.record_at(Some(NetworkError), 180);
+        assert_eq!(breaker.state_at(180), Open);
+
+        // The next one succeeds.
+        assert!(breaker.allow_at(230));
+        breaker.record_at(None, 230);
+        assert_eq!(breaker.state_at(230), Closed);
+        assert_eq!(breaker.failure_rate(), 0.0);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut breaker =
+            CircuitBreaker::new(2, 1.0, Duration::nanoseconds(50));
+        breaker.record_at(Some(InternalServerError), 100);
+        breaker.record_at(Some(NetworkError), 110);
+        breaker.record_at(None, 120);
+        // Only the last two calls count, for every code.
+        assert_eq!(breaker.failures(InternalServerError), 0);
+        assert_eq!(breaker.code_rate(NetworkError), 0.5);
+        assert_eq!(breaker.failure_rate(), 0.5);
+        assert_eq!(breaker.state_at(120), Closed);
+    }
+
+    #[test]
+    fn test_cancelled_probe() {
+        let mut breaker =
+            CircuitBreaker::new(1, 1.0, Duration::nanoseconds(50));
+        breaker.record_at(Some(NetworkError), 100);
+        assert!(breaker.allow_at(150));
+        assert!(breaker.probing());
+        assert!(!breaker.allow_at(160));
+        // The probe is cancelled, so another goes through.
+        breaker.cancel_probe();
+        assert!(breaker.allow_at(170));
+        breaker.record_at(None, 180);
+        assert_eq!(breaker.state_at(180), Closed);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_threshold_out_of_range() {
+        CircuitBreaker::new(1, 1.5, Duration::seconds(1));
+    }
+
+    #[test]
+    fn test_trips_on_unavailable() {
+        let mut breaker =
+            CircuitBreaker::new(2, 0.5, Duration::nanoseconds(50));
+        assert!(breaker.allow_at(100));
+        breaker.record_at(Some(Unavailable), 100);
+        assert_eq!(breaker.state_at(100), Open);
+        assert_eq!(breaker.failures(Unavailable), 1);
+        assert!(!breaker.allow_at(110));
+    }
+}
@@ -0,0 +1,1759 @@
+use circuit::CircuitBreaker;
+use codec::{mod, Codec};
+use deadline::Deadline;
+use error::{Error, Cancelled, DeadlineExceeded, FailedPrecondition};
+use error::{NetworkError, ResourceExhausted, Unavailable};
+use flow::{CreditWindow, Grants, INITIAL_CREDITS};
+use future::{AsyncFuture, CancelToken, Fulfiller, Future, Promise};
+use liveness::{Liveness, Peer};
+use reactor::{Reactor, TimerHandle};
+use retry::RetryPolicy;
+use server::Context;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+use stream::Stream;
+use wire::{mod, Credit, Envelope, Heartbeat, Kind, Metadata, Request,
+           SendQueue, StreamEnd, StreamItem, StreamRequest};
+use zmq;
+
+type ReplyHandler = proc(Result<Vec<u8>, Error>):'static -> ();
+
+// Receives every item of a stream, then `Ok(None)` or the error ending it.
+// Fails on an item it can't decode, without ending the stream.
+type StreamHandler =
+    Box<FnMut(Result<Option<Vec<u8>>, Error>) -> Result<(), Error> + 'static>;
+
+// Gives the next request piped into a call, encoded, or `None` at the end.
+type RequestSource = Box<FnMut() -> Option<Result<Vec<u8>, Error>> + 'static>;
+
+struct Staged {
+    method: &'static str,
+    // `StreamRequest` if the requests follow, see `call_client_stream`.
+    kind: Kind,
+    deadline: Option<Deadline>,
+    idempotent: bool,
+    payload: Result<Vec<u8>, Error>,
+}
+
+/// A staged call which may be sent, see `Channel::admit`.
+struct Admitted {
+    method: &'static str,
+    kind: Kind,
+    deadline: Option<Deadline>,
+    idempotent: bool,
+    payload: Vec<u8>,
+    // Whether the call is a probe of the circuit breaker.
+    probe: bool,
+}
+
+struct Pending {
+    deadline: Option<Deadline>,
+    handler: ReplyHandler,
+    // Set for calls which may be retried.
+    retry: Option<Retry>,
+    // Whether the current attempt is a probe of the circuit breaker.
+    probe: bool,
+}
+
+impl Pending {
+    fn backing_off(&self) -> bool {
+        self.retry.as_ref().map_or(false, |retry| retry.backoff_until.is_some())
+    }
+
+    /// When the call next needs attention: when it is resent after backing
+    /// off, or when its current attempt or the call itself expires.
+    fn next_expiry(&self) -> Option<Deadline> {
+        match self.retry {
+            Some(Retry { backoff_until: Some(until), .. }) => {
+                Deadline::earliest(Some(until), self.deadline)
+            },
+            Some(ref retry) => retry.attempt_deadline,
+            None => self.deadline,
+        }
+    }
+}
+
+/// A call to a streaming method. Streams are not retried, as their owner may
+/// have seen part of them already.
+struct Streaming {
+    deadline: Option<Deadline>,
+    // Taken out while it runs.
+    handler: Option<StreamHandler>,
+    // Whether the call is a probe of the circuit breaker.
+    probe: bool,
+}
+
+// Calls which the circuit breaker may have let through as probes.
+trait Probe {
+    fn probe(&self) -> bool;
+}
+
+impl Probe for Pending {
+    fn probe(&self) -> bool { self.probe }
+}
+
+impl Probe for Streaming {
+    fn probe(&self) -> bool { self.probe }
+}
+
+/// The requests streamed by a call, which wait for the credits granted by
+/// the endpoint.
+struct Outbound {
+    window: CreditWindow,
+    // Set while requests are piped into the call, see `RequestSink::pipe`.
+    source: Option<RequestSource>,
+    token: CancelToken,
+}
+
+/// What it takes to send a call again. Retries reuse the request id, so a
+/// late reply to an earlier attempt resolves the call too.
+struct Retry {
+    request: Envelope,
+    attempts: uint,
+    attempt_timeout: Option<Duration>,
+    attempt_deadline: Option<Deadline>,
+    backoff_until: Option<Deadline>,
+}
+
+/// Client side of a DEALER connection to a service endpoint, encoding
+/// messages with `C`.
+///
+/// Every call is tagged with a fresh request id so replies can be matched to
+/// calls regardless of the order in which they arrive, and so that streams
+/// of requests and responses share the connection. Requests which the
+/// socket cannot take right away are queued, see `handle_events`.
+///
+/// With `set_retry_policy`, failed calls to idempotent methods are retried.
+/// With `set_circuit_breaker`, calls fail fast while the endpoint is down.
+/// With `set_liveness`, the channel checks that the endpoint is alive by
+/// exchanging heartbeats with it, see `heartbeat`. Channels made with
+/// `connect` can also replace a connection to a dead endpoint, see
+/// `reconnect`.
+///
+/// Streams are flow controlled, see `flow`: the channel grants the endpoint
+/// credits as the responses are taken from a `Stream`, and streamed requests
+/// wait for the credits the endpoint grants, see `RequestSink`.
+pub struct Channel<C> {
+    socket: zmq::Socket,
+    send_queue: SendQueue,
+    codec: C,
+    metadata: Metadata,
+    timeout: Option<Duration>,
+    next_deadline: Option<Deadline>,
+    next_idempotent: bool,
+    retry_policy: Option<RetryPolicy>,
+    breaker: Option<CircuitBreaker>,
+    // Probes cancelled since the breaker last let a call through, which it
+    // gives up on then.
+    cancelled_probes: Rc<Cell<uint>>,
+    next_id: u64,
+    staged: Option<Staged>,
+    // Both shared with the cancellation callbacks of the calls, which remove
+    // them.
+    pending: Rc<RefCell<HashMap<u64, Pending>>>,
+    streams: Rc<RefCell<HashMap<u64, Streaming>>>,
+    // The calls streaming requests, shared with their sinks and cancellation
+    // callbacks.
+    outbound: Rc<RefCell<HashMap<u64, Outbound>>>,
+    peer: Option<Peer>,
+    // Where the socket is connected, if the channel made the connection, see
+    // `reconnect`.
+    address: Option<String>,
+}
+
+impl<C: Clone + 'static> Channel<C> {
+    pub fn new(socket: zmq::Socket, codec: C) -> Channel<C> {
+        Channel {
+            socket: socket,
+            send_queue: SendQueue::new(),
+            codec: codec,
+            metadata: Metadata::new(),
+            timeout: None,
+            next_deadline: None,
+            next_idempotent: false,
+            retry_policy: None,
+            breaker: None,
+            cancelled_probes: Rc::new(Cell::new(0)),
+            next_id: 0,
+            staged: None,
+            pending: Rc::new(RefCell::new(HashMap::new())),
+            streams: Rc::new(RefCell::new(HashMap::new())),
+            outbound: Rc::new(RefCell::new(HashMap::new())),
+            peer: None,
+            address: None,
+        }
+    }
+
+    /// A channel with a DEALER socket connected to `address`, which it can
+    /// `reconnect` to.
+    pub fn connect(ctx: &mut zmq::Context, address: &str, codec: C)
+            -> Result<Channel<C>, Error> {
+        let socket = try!(connect(ctx, address));
+        let mut channel = Channel::new(socket, codec);
+        channel.address = Some(address.to_string());
+        Ok(channel)
+    }
+
+    /// Replaces the socket with a fresh connection to the endpoint, e.g. once
+    /// it stopped responding to heartbeats: ZeroMQ reconnects on its own
+    /// when a connection breaks, but not when it is merely half-open. Only
+    /// for channels made with `connect`.
+    ///
+    /// Calls whose requests went out on the old connection fail with
+    /// `NetworkError`, or are retried, unless they already failed or are
+    /// backing off; messages still queued are sent on the new connection.
+    /// The old socket is closed, and a reactor polling the channel through
+    /// `poll_item` polls the new one from its next poll on, so call this from
+    /// a timer or a handler of the channel itself, not from the handler of
+    /// another source.
+    pub fn reconnect(&mut self, ctx: &mut zmq::Context) -> Result<(), Error> {
+        let socket = match self.address {
+            Some(ref address) => try!(connect(ctx, address.as_slice())),
+            None => return Err(Error::with_desc(
+                FailedPrecondition, "channel has no address to reconnect to")),
+        };
+        info!("client: reconnecting to {}", self.address.as_ref().unwrap());
+        self.socket = socket;
+        self.peer = self.peer.as_ref().map(|peer| {
+            Peer::new(peer.liveness().clone())
+        });
+
+        let mut queued = HashSet::new();
+        self.send_queue.each(|frames| {
+            match Envelope::peek(frames) {
+                Ok((_, Request, id)) | Ok((_, StreamRequest, id)) => {
+                    queued.insert(id);
+                },
+                _ => {},
+            }
+        });
+        let sent: Vec<u64> = self.pending.borrow().iter()
+            .filter(|&(id, pending)| {
+                !queued.contains(id) && !pending.backing_off()
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in sent.iter() {
+            // Handlers may cancel other calls, removing them.
+            let pending = self.pending.borrow_mut().remove(&id);
+            match pending {
+                Some(pending) => self.resolve(id, pending, Err(replaced())),
+                None => {},
+            }
+        }
+        let sent: Vec<u64> = self.streams.borrow().keys()
+            .filter(|id| !queued.contains(*id))
+            .map(|&id| id)
+            .collect();
+        for id in sent.iter() {
+            let streaming = self.streams.borrow_mut().remove(id);
+            match streaming {
+                Some(streaming) => {
+                    self.end_stream(*id, streaming, Err(replaced()));
+                },
+                None => {},
+            }
+        }
+        Ok(())
+    }
+
+    pub fn call<Req, Resp>(&mut self, method: &'static str, request: &Req)
+            -> Future<Result<Resp, Error>, Channel<C>>
+            where C: Codec<Req> + Codec<Resp> {
+        let payload = codec::encode(&self.codec, request);
+        self.stage(method, Request, payload);
+        Future::new(self)
+    }
+
+    /// Calls a streaming method. The stream has the deadline a call would
+    /// have, for all of its responses, and cancelling it tells the endpoint
+    /// to stop. Streams are never retried, but go through the circuit
+    /// breaker.
+    pub fn call_stream<Req, Resp: 'static>(&mut self, method: &'static str,
+                                           request: &Req) -> Stream<Resp>
+            where C: Codec<Req> + Codec<Resp> {
+        let payload = codec::encode(&self.codec, request);
+        self.stage(method, Request, payload);
+        let (_, _, stream) = self.open_stream();
+        stream
+    }
+
+    /// Calls a method taking a stream of requests, which are sent through
+    /// the returned sink. The call resolves once the endpoint replies,
+    /// normally after the sink was closed. Such calls are never retried.
+    pub fn call_client_stream<Req, Resp>(&mut self, method: &'static str)
+            -> (RequestSink<Req, C>, AsyncFuture<Result<Resp, Error>>)
+            where C: Codec<Req> + Codec<Resp> {
+        self.stage(method, StreamRequest, Ok(Vec::new()));
+        let (future, promise) = Future::new_with_promise();
+        let token = promise.cancel_token();
+        let id = self.send_call(promise);
+        (self.request_sink(id, token), future.async())
+    }
+
+    /// Calls a method taking a stream of requests and streaming its
+    /// responses, see `call_client_stream` and `call_stream`. Responses may
+    /// arrive before the sink is closed.
+    pub fn call_bidi_stream<Req, Resp: 'static>(&mut self,
+                                                method: &'static str)
+            -> (RequestSink<Req, C>, Stream<Resp>)
+            where C: Codec<Req> + Codec<Resp> {
+        self.stage(method, StreamRequest, Ok(Vec::new()));
+        let (id, token, stream) = self.open_stream();
+        (self.request_sink(id, token), stream)
+    }
+
+    /// Sends the staged call, opening a stream of responses, and returns its
+    /// id unless the call failed right away.
+    fn open_stream<Resp: 'static>(&mut self)
+            -> (Option<u64>, CancelToken, Stream<Resp>)
+            where C: Codec<Resp> {
+        let (stream, sender) = Stream::new_with_sender();
+        let token = sender.cancel_token();
+        let Admitted { method, kind, deadline, payload, probe, .. } =
+            match self.admit() {
+                Ok(admitted) => admitted,
+                Err(err) => {
+                    sender.finish(Err(err));
+                    return (None, token, stream);
+                }
+            };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let codec = self.codec.clone();
+        let mut request = Envelope::request(id,
+                                            deadline.map(|d| d.remaining()),
+                                            method.to_string(),
+                                            self.metadata.clone(), payload);
+        request.kind = kind;
+        // Grants credits as the owner of the stream takes the responses, so
+        // the endpoint never gets too far ahead of it.
+        let streams = self.streams.clone();
+        let send_queue = self.send_queue.clone();
+        let mut grants = Grants::new(INITIAL_CREDITS);
+        sender.on_taken(box move |&mut:| {
+            if !streams.borrow().contains_key(&id) { return; }
+            match grants.consumed() {
+                Some(credits) => send_queue.push(
+                    Envelope::credit(id, credits).encode(Vec::new())),
+                None => {},
+            }
+        });
+        let handler: StreamHandler =
+            box move |&mut: event: Result<Option<Vec<u8>>, Error>|
+                    -> Result<(), Error> {
+                match event {
+                    Ok(Some(payload)) => {
+                        sender.send(try!(codec::decode(&codec,
+                                                       payload.as_slice())));
+                    },
+                    Ok(None) => sender.finish(Ok(())),
+                    Err(err) => sender.finish(Err(err)),
+                }
+                Ok(())
+            };
+        self.streams.borrow_mut().insert(id, Streaming {
+            deadline: deadline,
+            handler: Some(handler),
+            probe: probe,
+        });
+        self.send_queue.push(request.encode(Vec::new()));
+        // On failure every pending call, this one included, has been failed.
+        let _ = self.flush();
+
+        self.forget_on_cancel(&token, &self.streams, id);
+        (Some(id), token, stream)
+    }
+
+    fn request_sink<T>(&self, id: Option<u64>, token: CancelToken)
+            -> RequestSink<T, C> {
+        match id {
+            // Unless the call already failed while it was sent.
+            Some(id) if self.pending.borrow().contains_key(&id) ||
+                    self.streams.borrow().contains_key(&id) => {
+                self.outbound.borrow_mut().insert(id, Outbound {
+                    window: CreditWindow::new(INITIAL_CREDITS),
+                    source: None,
+                    token: token.clone(),
+                });
+                let outbound = self.outbound.clone();
+                token.on_cancel(proc() { outbound.borrow_mut().remove(&id); });
+            },
+            _ => {},
+        }
+        RequestSink {
+            id: id,
+            token: token,
+            codec: self.codec.clone(),
+            send_queue: self.send_queue.clone(),
+            outbound: self.outbound.clone(),
+            closed: false,
+        }
+    }
+
+    /// Metadata sent along with every call on this channel.
+    pub fn metadata(&mut self) -> &mut Metadata { &mut self.metadata }
+
+    /// Sets the time budget given to every call on this channel.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets a deadline for the next call only. If the channel has a timeout
+    /// the earlier of the two applies.
+    pub fn deadline(&mut self, deadline: Deadline) -> &mut Channel<C> {
+        self.next_deadline =
+            Deadline::earliest(self.next_deadline, Some(deadline));
+        self
+    }
+
+    /// Marks the next call as safe to send more than once, so it is retried
+    /// according to the retry policy.
+    pub fn idempotent(&mut self) -> &mut Channel<C> {
+        self.next_idempotent = true;
+        self
+    }
+
+    /// Sets how calls marked `idempotent` are retried, or disables retries
+    /// with `None`.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// Sets the circuit breaker which every call, retries included, goes
+    /// through, failing with `Unavailable` while it is open. Disables it
+    /// with `None`.
+    pub fn set_circuit_breaker(&mut self, breaker: Option<CircuitBreaker>) {
+        self.breaker = breaker;
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.breaker.as_ref()
+    }
+
+    /// Propagates the deadline of the request being handled in `ctx` to the
+    /// next call.
+    pub fn within(&mut self, ctx: &Context) -> &mut Channel<C> {
+        match ctx.deadline() {
+            Some(deadline) => self.deadline(deadline),
+            None => self,
+        }
+    }
+
+    pub fn poll_item<'b>(&self, events: i16) -> zmq::PollItem<'b> {
+        self.socket.as_poll_item(events)
+    }
+
+    /// The queue of requests waiting for the socket to become writable.
+    pub fn send_queue(&self) -> SendQueue { self.send_queue.clone() }
+
+    /// Enables heartbeats, or disables them with `None`.
+    pub fn set_liveness(&mut self, liveness: Option<Liveness>) {
+        self.peer = liveness.map(Peer::new);
+    }
+
+    /// Whether the endpoint answered heartbeats recently. Always true without
+    /// heartbeats.
+    pub fn alive(&self) -> bool {
+        self.peer.as_ref().map_or(true, |peer| peer.alive())
+    }
+
+    /// Sends a heartbeat. Schedule it with `Reactor::schedule_repeating`
+    /// every `Liveness::interval`.
+    ///
+    /// If nothing was heard from the endpoint within `Liveness::timeout`, it
+    /// is considered dead and all pending calls fail with `NetworkError`, or
+    /// are retried. The endpoint is alive again as soon as it answers.
+    /// Channels made with `connect` can replace the connection instead, see
+    /// `heartbeat_reconnect`.
+    pub fn heartbeat(&mut self) -> Result<(), Error> {
+        if self.check_peer() {
+            self.fail_pending(&Error::with_desc(
+                NetworkError, "endpoint stopped responding to heartbeats"));
+        }
+        self.send_heartbeat()
+    }
+
+    /// Like `heartbeat`, but reconnects once the endpoint is found dead, see
+    /// `reconnect`, and returns whether it did. Calls already sent fail with
+    /// `NetworkError` once, by `reconnect`.
+    pub fn heartbeat_reconnect(&mut self, ctx: &mut zmq::Context)
+            -> Result<bool, Error> {
+        let died = self.check_peer();
+        if died {
+            try!(self.reconnect(ctx));
+        }
+        try!(self.send_heartbeat());
+        Ok(died)
+    }
+
+    // Whether the endpoint was just found dead.
+    fn check_peer(&mut self) -> bool {
+        let died = match self.peer {
+            Some(ref mut peer) => peer.check(),
+            None => false,
+        };
+        if died {
+            warn!("client: endpoint stopped responding to heartbeats");
+        }
+        died
+    }
+
+    fn send_heartbeat(&mut self) -> Result<(), Error> {
+        if self.peer.is_none() { return Ok(()); }
+        self.send_queue.push(Envelope::heartbeat().encode(Vec::new()));
+        self.flush()
+    }
+
+    /// Flushes queued requests on `POLLOUT` and processes a reply on
+    /// `POLLIN`. Fails all pending calls if the socket returns an error.
+    pub fn handle_events(&mut self, revents: i16) -> Result<(), Error> {
+        if revents & zmq::POLLOUT != 0 {
+            try!(self.flush());
+        }
+        if revents & zmq::POLLIN != 0 {
+            try!(self.process());
+        }
+        Ok(())
+    }
+
+    /// The number of calls waiting for a reply or for the end of their
+    /// stream.
+    pub fn pending(&self) -> uint {
+        self.pending.borrow().len() + self.streams.borrow().len()
+    }
+
+    /// The earliest deadline among pending calls and streams, including the
+    /// ones of retried attempts and the ends of backoffs.
+    pub fn next_expiry(&self) -> Option<Deadline> {
+        let calls = self.pending.borrow().values()
+            .fold(None, |earliest, pending| {
+                Deadline::earliest(earliest, pending.next_expiry())
+            });
+        self.streams.borrow().values().fold(calls, |earliest, streaming| {
+            Deadline::earliest(earliest, streaming.deadline)
+        })
+    }
+
+    /// Fails every pending call or stream whose deadline has passed with
+    /// `DeadlineExceeded`, or retries it if the deadline was that of an
+    /// attempt, returning how many there were. Also resends calls which are
+    /// done backing off. The endpoint is told to stop sending the expired
+    /// streams.
+    ///
+    /// Synchronous calls expire on their own; channels driven by a `Reactor`
+    /// have it called as their calls expire by `register_expiry`.
+    pub fn expire(&mut self) -> uint {
+        let due: Vec<u64> = self.pending.borrow().iter()
+            .filter(|&(_, pending)| {
+                pending.next_expiry().map_or(false, |d| d.expired())
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        let mut expired = 0;
+        for &id in due.iter() {
+            // Resending may fail and resolve other calls.
+            let pending = self.pending.borrow_mut().remove(&id);
+            let pending = match pending {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let over = pending.deadline.map_or(false, |d| d.expired());
+            if pending.backing_off() && !over {
+                self.resend(id, pending);
+            } else {
+                expired += 1;
+                self.resolve(id, pending, Err(deadline_exceeded()));
+            }
+        }
+
+        let due: Vec<u64> = self.streams.borrow().iter()
+            .filter(|&(_, streaming)| {
+                streaming.deadline.map_or(false, |d| d.expired())
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in due.iter() {
+            let streaming = self.streams.borrow_mut().remove(id);
+            let streaming = match streaming {
+                Some(streaming) => streaming,
+                None => continue,
+            };
+            expired += 1;
+            self.send_queue.push(Envelope::cancel(*id).encode(Vec::new()));
+            self.end_stream(*id, streaming, Err(deadline_exceeded()));
+        }
+        expired
+    }
+
+    /// Has `reactor` call `expire` on `channel` as soon as the earliest
+    /// deadline among its pending calls passes.
+    pub fn register_expiry<'a, 'b>(channel: &'a RefCell<Channel<C>>,
+                                   reactor: &mut Reactor<'a, 'b>)
+            -> TimerHandle {
+        reactor.schedule_dynamic(box |&:| channel.borrow().next_expiry(),
+                                 box |&mut:| { channel.borrow_mut().expire(); })
+    }
+
+    /// Receives a single reply and resolves the call it belongs to, or passes
+    /// it on to its stream. Credits go to the requests streamed by their
+    /// call. Fails all pending calls if the socket returns an error.
+    pub fn process(&mut self) -> Result<(), Error> {
+        let frames = match wire::recv_frames(&mut self.socket, 0) {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.fail_pending(&err);
+                return Err(err);
+            }
+        };
+        match self.peer {
+            Some(ref mut peer) if peer.seen() => {
+                info!("client: endpoint is responding again");
+            },
+            _ => {},
+        }
+        let envelope = match Envelope::decode(frames) {
+            Ok((_, envelope)) => envelope,
+            Err(err) => {
+                warn!("client: dropping malformed reply: {}", err);
+                return Ok(());
+            }
+        };
+        if envelope.kind == Heartbeat { return Ok(()); }
+        if envelope.kind == Credit { return self.grant(envelope); }
+        if self.streams.borrow().contains_key(&envelope.id) {
+            self.stream_event(envelope);
+            return Ok(());
+        }
+        let pending = self.pending.borrow_mut().remove(&envelope.id);
+        match pending {
+            Some(pending) => {
+                self.resolve(envelope.id, pending, envelope.into_result());
+            },
+            None => debug!("client: dropping reply to unknown, expired or \
+                            cancelled request {}", envelope.id),
+        }
+        Ok(())
+    }
+
+    /// Sends the staged call and waits until it is resolved.
+    fn sync_call<T>(&mut self, promise: Promise<Result<T, Error>>)
+            where C: Codec<T> {
+        let id = match self.send_call(promise) {
+            Some(id) => id,
+            None => return,
+        };
+        loop {
+            let deadline = match self.pending.borrow().get(&id) {
+                Some(pending) => pending.next_expiry(),
+                None => break,
+            };
+            match self.wait(deadline) {
+                Ok(()) => {},
+                Err(err) => {
+                    let pending = self.pending.borrow_mut().remove(&id);
+                    match pending {
+                        Some(pending) => (pending.handler)(Err(err)),
+                        None => {},
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Blocks until a reply arrives or `deadline` passes, then processes the
+    /// reply or expires calls accordingly.
+    fn wait(&mut self, deadline: Option<Deadline>) -> Result<(), Error> {
+        let timeout_ms = deadline.map_or(-1, |d| d.timeout_ms());
+        let events = if self.send_queue.is_empty() {
+            zmq::POLLIN
+        } else {
+            zmq::POLLIN | zmq::POLLOUT
+        };
+        let mut items = [self.poll_item(events)];
+        try!(zmq::poll(&mut items, timeout_ms).map_err(Error::from_zmq));
+        let revents = items[0].get_revents();
+        if revents == 0 {
+            self.expire();
+            Ok(())
+        } else {
+            self.handle_events(revents)
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.send_queue.flush(&mut self.socket) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.fail_pending(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn send_call<T>(&mut self, promise: Promise<Result<T, Error>>)
+            -> Option<u64>
+            where C: Codec<T> {
+        let token = promise.cancel_token();
+        if token.is_cancelled() {
+            self.staged = None;
+            return None;
+        }
+        let Admitted { method, kind, deadline, idempotent, payload, probe } =
+            match self.admit() {
+                Ok(admitted) => admitted,
+                Err(err) => {
+                    promise.fulfill(Err(err));
+                    return None;
+                }
+            };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let codec = self.codec.clone();
+        let mut request = Envelope::request(id, None, method.to_string(),
+                                            self.metadata.clone(), payload);
+        request.kind = kind;
+        let retry = match self.retry_policy {
+            Some(ref policy) if idempotent => {
+                let timeout = policy.attempt_timeout();
+                Some(Retry {
+                    request: request.clone(),
+                    attempts: 1,
+                    attempt_timeout: timeout,
+                    attempt_deadline: Deadline::earliest(
+                        deadline, timeout.map(Deadline::after)),
+                    backoff_until: None,
+                })
+            },
+            _ => None,
+        };
+        request.budget = match retry {
+            Some(ref retry) => retry.attempt_deadline,
+            None => deadline,
+        }.map(|d| d.remaining());
+        self.pending.borrow_mut().insert(id, Pending {
+            deadline: deadline,
+            handler: proc(reply) {
+                promise.fulfill(reply.and_then(|payload| {
+                    codec::decode(&codec, payload.as_slice())
+                }));
+            },
+            retry: retry,
+            probe: probe,
+        });
+        self.send_queue.push(request.encode(Vec::new()));
+        // On failure every pending call, this one included, has been failed.
+        let _ = self.flush();
+
+        self.forget_on_cancel(&token, &self.pending, id);
+        Some(id)
+    }
+
+    /// Has cancelling `token` forget call `id` among `calls` right away and
+    /// tell the server, which may still be working on it, unless the call is
+    /// already over.
+    fn forget_on_cancel<T: Probe + 'static>(
+            &self, token: &CancelToken, calls: &Rc<RefCell<HashMap<u64, T>>>,
+            id: u64) {
+        let calls = calls.clone();
+        let send_queue = self.send_queue.clone();
+        let cancelled_probes = self.cancelled_probes.clone();
+        token.on_cancel(proc() {
+            let call = calls.borrow_mut().remove(&id);
+            match call {
+                Some(call) => {
+                    if call.probe() {
+                        cancelled_probes.set(cancelled_probes.get() + 1);
+                    }
+                    send_queue.push(Envelope::cancel(id).encode(Vec::new()));
+                },
+                None => {},
+            }
+        });
+    }
+
+    /// Hands the credits granted to a call to its requests, sending those
+    /// piped in which were waiting for them.
+    fn grant(&mut self, envelope: Envelope) -> Result<(), Error> {
+        let credits = match envelope.credits() {
+            Ok(credits) => credits,
+            Err(err) => {
+                warn!("client: dropping malformed credit: {}", err);
+                return Ok(());
+            }
+        };
+        match self.outbound.borrow_mut().get_mut(&envelope.id) {
+            Some(outbound) => outbound.window.grant(credits),
+            None => {
+                debug!("client: dropping credit for finished request {}",
+                       envelope.id);
+                return Ok(());
+            },
+        }
+        pump(&self.outbound, envelope.id, &self.send_queue);
+        self.flush()
+    }
+
+    /// Stages a call to `method` with the deadline of the next call, to be
+    /// sent once admitted. Calls whose requests are streamed can't be sent
+    /// again, so they are never idempotent.
+    fn stage(&mut self, method: &'static str, kind: Kind,
+             payload: Result<Vec<u8>, Error>) {
+        let deadline = Deadline::earliest(self.next_deadline.take(),
+                                          self.timeout.map(Deadline::after));
+        let idempotent = mem::replace(&mut self.next_idempotent, false);
+        self.staged = Some(Staged {
+            method: method,
+            kind: kind.clone(),
+            deadline: deadline,
+            idempotent: idempotent && kind == Request,
+            payload: payload,
+        });
+    }
+
+    /// Takes the staged call, failing it unless its request could be
+    /// encoded, its deadline hasn't passed and the circuit breaker lets it
+    /// through.
+    fn admit(&mut self) -> Result<Admitted, Error> {
+        let Staged { method, kind, deadline, idempotent, payload } =
+            self.staged.take().expect("No call was staged.");
+        let payload = try!(payload);
+        if deadline.map_or(false, |d| d.expired()) {
+            return Err(deadline_exceeded());
+        }
+        let probe = match self.allow() {
+            Some(probe) => probe,
+            None => return Err(unavailable()),
+        };
+        Ok(Admitted {
+            method: method,
+            kind: kind,
+            deadline: deadline,
+            idempotent: idempotent,
+            payload: payload,
+            probe: probe,
+        })
+    }
+
+    /// Whether the circuit breaker lets a call through, and if so whether as
+    /// a probe.
+    fn allow(&mut self) -> Option<bool> {
+        let breaker = match self.breaker {
+            Some(ref mut breaker) => breaker,
+            None => return Some(false),
+        };
+        for _ in range(0, self.cancelled_probes.get()) {
+            breaker.cancel_probe();
+        }
+        self.cancelled_probes.set(0);
+        if breaker.allow() { Some(breaker.probing()) } else { None }
+    }
+
+    fn fail_pending(&mut self, err: &Error) {
+        let pending = mem::replace(&mut *self.pending.borrow_mut(),
+                                   HashMap::new());
+        for (id, pending) in pending.into_iter() {
+            self.resolve(id, pending, Err(Error::with_desc(
+                err.code(), err.desc().to_string())));
+        }
+        let streams = mem::replace(&mut *self.streams.borrow_mut(),
+                                   HashMap::new());
+        for (id, streaming) in streams.into_iter() {
+            self.end_stream(id, streaming, Err(Error::with_desc(
+                err.code(), err.desc().to_string())));
+        }
+    }
+
+    /// Passes a message of a stream on to its handler, forgetting the stream
+    /// once it ends.
+    fn stream_event(&mut self, envelope: Envelope) {
+        let id = envelope.id;
+        if envelope.kind == StreamItem {
+            // The owner of the stream may cancel it, removing it, while the
+            // handler runs.
+            let handler = match self.streams.borrow_mut().get_mut(&id) {
+                Some(streaming) => streaming.handler.take(),
+                None => None,
+            };
+            let mut handler = match handler {
+                Some(handler) => handler,
+                None => return,
+            };
+            let result = handler.call_mut((Ok(Some(envelope.payload)),));
+            match self.streams.borrow_mut().get_mut(&id) {
+                Some(streaming) => streaming.handler = Some(handler),
+                None => return,
+            }
+            // An item which can't be decoded ends the stream, and tells the
+            // endpoint to stop sending it.
+            let err = match result {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            let streaming = self.streams.borrow_mut().remove(&id).unwrap();
+            self.send_queue.push(Envelope::cancel(id).encode(Vec::new()));
+            self.end_stream(id, streaming, Err(err));
+            return;
+        }
+        let result = match envelope.kind {
+            StreamEnd => Ok(()),
+            _ => match envelope.into_result() {
+                Ok(_) => Err(Error::with_desc(NetworkError,
+                                              "expected stream message")),
+                Err(err) => Err(err),
+            },
+        };
+        let streaming = self.streams.borrow_mut().remove(&id);
+        match streaming {
+            Some(streaming) => self.end_stream(id, streaming, result),
+            None => {},
+        }
+    }
+
+    fn end_stream(&mut self, id: u64, streaming: Streaming,
+                  result: Result<(), Error>) {
+        // The call is over, and so are its requests.
+        self.outbound.borrow_mut().remove(&id);
+        match self.breaker {
+            Some(ref mut breaker) => {
+                breaker.record(result.as_ref().err().map(|err| err.code()));
+            },
+            None => {},
+        }
+        match streaming.handler {
+            Some(mut handler) => {
+                let _ = handler.call_mut((result.map(|()| None),));
+            },
+            None => {},
+        }
+    }
+
+    /// Resolves a call with `result`, unless it is a failure after which the
+    /// call is retried.
+    fn resolve(&mut self, id: u64, mut pending: Pending,
+               result: Result<Vec<u8>, Error>) {
+        // Calls streaming requests are never retried, so their requests are
+        // over too.
+        self.outbound.borrow_mut().remove(&id);
+        match self.breaker {
+            Some(ref mut breaker) => {
+                breaker.record(result.as_ref().err().map(|err| err.code()));
+            },
+            None => {},
+        }
+        pending.probe = false;
+        let err = match result {
+            Ok(payload) => return (pending.handler)(Ok(payload)),
+            Err(err) => err,
+        };
+        match self.backoff(&pending, &err) {
+            Some(backoff) => {
+                debug!("client: retrying request {} in {}: {}",
+                       id, backoff, err);
+                pending.retry.as_mut().unwrap().backoff_until =
+                    Some(Deadline::after(backoff));
+                self.pending.borrow_mut().insert(id, pending);
+            },
+            None => (pending.handler)(Err(err)),
+        }
+    }
+
+    /// How long to back off before retrying a call which failed with `err`,
+    /// if it may be retried at all.
+    fn backoff(&self, pending: &Pending, err: &Error) -> Option<Duration> {
+        let (policy, retry) = match (&self.retry_policy, &pending.retry) {
+            (&Some(ref policy), &Some(ref retry)) => (policy, retry),
+            _ => return None,
+        };
+        if retry.attempts >= policy.max_attempts() ||
+                !policy.retryable(err.code()) {
+            return None;
+        }
+        let backoff = policy.backoff(retry.attempts);
+        match pending.deadline {
+            Some(deadline) if deadline.remaining() <= backoff => None,
+            _ => Some(backoff),
+        }
+    }
+
+    fn resend(&mut self, id: u64, mut pending: Pending) {
+        pending.probe = match self.allow() {
+            Some(probe) => probe,
+            None => return (pending.handler)(Err(unavailable())),
+        };
+        let deadline = pending.deadline;
+        {
+            let retry = pending.retry.as_mut().unwrap();
+            retry.attempts += 1;
+            retry.backoff_until = None;
+            retry.attempt_deadline = Deadline::earliest(
+                deadline, retry.attempt_timeout.map(Deadline::after));
+            retry.request.budget =
+                retry.attempt_deadline.map(|d| d.remaining());
+            self.send_queue.push(retry.request.clone().encode(Vec::new()));
+        }
+        self.pending.borrow_mut().insert(id, pending);
+        // On failure the call has been failed or will be retried again.
+        let _ = self.flush();
+    }
+}
+
+impl<T, C: Codec<T> + Clone + 'static> Fulfiller<Result<T, Error>>
+        for Channel<C> {
+    fn sync(&mut self, promise: Promise<Result<T, Error>>) {
+        self.sync_call(promise);
+    }
+
+    fn async(&mut self, promise: Promise<Result<T, Error>>) {
+        self.send_call(promise);
+    }
+
+    /// Sends the call with a deadline no later than `deadline`, so the server
+    /// learns about it too, and waits for it.
+    fn sync_until(&mut self, promise: Promise<Result<T, Error>>,
+                  deadline: Deadline) {
+        match self.staged {
+            Some(ref mut staged) => {
+                staged.deadline =
+                    Deadline::earliest(staged.deadline, Some(deadline));
+            },
+            None => {},
+        }
+        self.sync_call(promise);
+    }
+}
+
+/// Sends the requests of a call taking a stream of them, see
+/// `Channel::call_client_stream`. Requests are queued on the channel's send
+/// queue, and go out whenever the channel flushes it.
+///
+/// Every request uses up one of the credits granted by the endpoint as it
+/// takes them; `send` fails while there are none left, whereas requests
+/// piped in with `pipe` wait for more.
+///
+/// Closing the sink half-closes the call: the endpoint learns that no more
+/// requests follow, while responses keep coming. Dropping it closes it too.
+pub struct RequestSink<T, C> {
+    // Unset when the call failed before it was sent, or once piped.
+    id: Option<u64>,
+    token: CancelToken,
+    codec: C,
+    send_queue: SendQueue,
+    // Shared with the channel, which hands out the credits.
+    outbound: Rc<RefCell<HashMap<u64, Outbound>>>,
+    closed: bool,
+}
+
+impl<T, C: Codec<T>> RequestSink<T, C> {
+    /// Queues a request. Fails with `Cancelled` once the call was cancelled,
+    /// and with `ResourceExhausted` while there are no credits left.
+    pub fn send(&mut self, request: &T) -> Result<(), Error> {
+        let id = match self.id {
+            _ if self.token.is_cancelled() => {
+                return Err(Error::with_desc(Cancelled, "call was cancelled"));
+            },
+            Some(id) => id,
+            None => return Err(Error::with_desc(
+                FailedPrecondition, "call failed before it was sent")),
+        };
+        let payload = {
+            let mut outbound = self.outbound.borrow_mut();
+            let window = match outbound.get_mut(&id) {
+                Some(outbound) => &mut outbound.window,
+                None => return Err(Error::with_desc(FailedPrecondition,
+                                                    "call already ended")),
+            };
+            if window.credits() == 0 {
+                return Err(Error::with_desc(
+                    ResourceExhausted, "no credits left for the request"));
+            }
+            let payload = try!(codec::encode(&self.codec, request));
+            window.take();
+            payload
+        };
+        self.send_queue.push(
+            Envelope::stream_item(id, payload).encode(Vec::new()));
+        Ok(())
+    }
+}
+
+impl<T: 'static, C: Codec<T> + Clone + 'static> RequestSink<T, C> {
+    /// Sends the requests taken from `requests`, pausing whenever the
+    /// credits run out until the endpoint grants more, and half-closes the
+    /// call at the end. Requests which can't be encoded cancel the call.
+    pub fn pipe<I: Iterator<T> + 'static>(mut self, requests: I) {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return,
+        };
+        let codec = self.codec.clone();
+        let mut requests = requests;
+        let source: RequestSource = box move |&mut:| {
+            requests.next().map(|request| codec::encode(&codec, &request))
+        };
+        match self.outbound.borrow_mut().get_mut(&id) {
+            Some(outbound) => outbound.source = Some(source),
+            None => return,
+        }
+        pump(&self.outbound, id, &self.send_queue);
+    }
+}
+
+impl<T, C> RequestSink<T, C> {
+    /// The number of requests which may be sent right away.
+    pub fn credits(&self) -> u32 {
+        self.id.and_then(|id| {
+            self.outbound.borrow().get(&id).map(|outbound| {
+                outbound.window.credits()
+            })
+        }).unwrap_or(0)
+    }
+
+    /// Half-closes the call.
+    pub fn close(mut self) { self.half_close(); }
+
+    fn half_close(&mut self) {
+        if self.closed { return; }
+        self.closed = true;
+        match self.id {
+            Some(id) if !self.token.is_cancelled() => {
+                self.send_queue.push(
+                    Envelope::stream_end(id, Ok(())).encode(Vec::new()));
+            },
+            _ => {},
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<T, C> Drop for RequestSink<T, C> {
+    fn drop(&mut self) { self.half_close(); }
+}
+
+/// Sends the requests piped into call `id` while it has credits left, and
+/// half-closes the call after the last one. Requests which can't be encoded
+/// cancel the call.
+fn pump(outbound: &Rc<RefCell<HashMap<u64, Outbound>>>, id: u64,
+        send_queue: &SendQueue) {
+    loop {
+        // The source runs with the calls released, as it may cancel its own.
+        let (source, token) = match outbound.borrow_mut().get_mut(&id) {
+            Some(outbound) => {
+                if outbound.window.credits() == 0 { return; }
+                (outbound.source.take(), outbound.token.clone())
+            },
+            None => return,
+        };
+        let mut source = match source {
+            Some(source) => source,
+            None => return,
+        };
+        match source.call_mut(()) {
+            Some(Ok(payload)) => {
+                match outbound.borrow_mut().get_mut(&id) {
+                    Some(outbound) => {
+                        outbound.window.take();
+                        outbound.source = Some(source);
+                    },
+                    None => return,
+                }
+                send_queue.push(
+                    Envelope::stream_item(id, payload).encode(Vec::new()));
+            },
+            Some(Err(err)) => {
+                error!("client: cancelling request {}: {}", id, err);
+                token.cancel();
+                return;
+            },
+            None => {
+                if outbound.borrow_mut().remove(&id).is_some() {
+                    send_queue.push(
+                        Envelope::stream_end(id, Ok(())).encode(Vec::new()));
+                }
+                return;
+            },
+        }
+    }
+}
+
+fn connect(ctx: &mut zmq::Context, address: &str)
+        -> Result<zmq::Socket, Error> {
+    let mut socket = try!(ctx.socket(zmq::DEALER).map_err(Error::from_zmq));
+    try!(socket.connect(address).map_err(Error::from_zmq));
+    Ok(socket)
+}
+
+fn deadline_exceeded() -> Error {
+    Error::with_desc(DeadlineExceeded, "deadline exceeded")
+}
+
+fn replaced() -> Error {
+    Error::with_desc(NetworkError, "connection to the endpoint was replaced")
+}
+
+fn unavailable() -> Error {
+    Error::with_desc(Unavailable, "circuit breaker is open")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Channel;
+    use circuit::{CircuitBreaker, Open};
+    use codec::JsonCodec;
+    use error::Error;
+    use deadline::Deadline;
+    use error::{Cancelled, DeadlineExceeded, FailedPrecondition};
+    use error::{NetworkError, InternalServerError, NotFound, Unavailable};
+    use error::ResourceExhausted;
+    use flow::INITIAL_CREDITS;
+    use future::AsyncFuture;
+    use liveness::Liveness;
+    use reactor::Reactor;
+    use retry::RetryPolicy;
+    use std::cell::RefCell;
+    use std::default::Default;
+    use std::io::timer;
+    use std::time::Duration;
+    use wire::{mod, Cancel, Credit, Envelope, Heartbeat, Request, StreamEnd,
+               StreamItem, StreamRequest};
+    use zmq;
+
+    // A channel connected to the ROUTER socket bound to `address`, which
+    // plays the endpoint.
+    fn connected_channel(ctx: &mut zmq::Context, address: &str)
+            -> (zmq::Socket, Channel<JsonCodec>) {
+        let mut server = ctx.socket(zmq::ROUTER).unwrap();
+        server.bind(address).unwrap();
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect(address).unwrap();
+        (server, Channel::new(socket, JsonCodec))
+    }
+
+    #[test]
+    fn test_out_of_order_replies() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-test");
+        channel.metadata().set("user".to_string(), "test".to_string());
+
+        let first: AsyncFuture<Result<String, Error>> =
+            channel.call("first", &1i).async();
+        let second: AsyncFuture<Result<String, Error>> =
+            channel.call("second", &2i).async();
+        assert_eq!(channel.pending(), 2);
+
+        let mut requests = Vec::new();
+        for _ in range(0u, 2) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            requests.push(Envelope::decode(frames).ok().unwrap());
+        }
+        for (route, request) in requests.into_iter().rev() {
+            assert_eq!(request.metadata.get("user"), Some("test"));
+            let payload = format!("\"{}\"", request.method).into_bytes();
+            let reply = Envelope::reply(request.id, Ok(payload)).encode(route);
+            wire::send_frames(&mut server, reply.as_slice()).unwrap();
+        }
+
+        channel.process().unwrap();
+        assert!(second.ready());
+        assert!(!first.ready());
+        channel.process().unwrap();
+        assert!(first.ready());
+        assert_eq!(channel.pending(), 0);
+
+        first.map(proc(reply) {
+            assert_eq!(reply.ok().unwrap().as_slice(), "first");
+        });
+        second.map(proc(reply) {
+            assert_eq!(reply.ok().unwrap().as_slice(), "second");
+        });
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-cancel-test");
+
+        let reply: AsyncFuture<Result<String, Error>> =
+            channel.call("slow", &()).async();
+        assert_eq!(channel.pending(), 1);
+        reply.cancel();
+        assert_eq!(channel.pending(), 0);
+        assert_eq!(channel.send_queue().len(), 1);
+        channel.handle_events(zmq::POLLOUT).unwrap();
+
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (route, request) = Envelope::decode(frames).ok().unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, cancel) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(cancel.kind, Cancel);
+        assert_eq!(cancel.id, request.id);
+
+        // A reply sent before the server saw the cancellation is dropped.
+        let reply = Envelope::reply(request.id, Ok(b"\"late\"".to_vec()));
+        wire::send_frames(&mut server, reply.encode(route).as_slice())
+            .unwrap();
+        channel.process().unwrap();
+        assert_eq!(channel.pending(), 0);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-stream-test");
+
+        let values = channel.call_stream::<int, int>("count", &2).collect();
+        let failed = channel.call_stream::<int, int>("count", &1).collect();
+        assert_eq!(channel.pending(), 2);
+
+        let mut requests = Vec::new();
+        for _ in range(0u, 2) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            requests.push(Envelope::decode(frames).ok().unwrap());
+        }
+        let (route, request) = requests.remove(0).unwrap();
+        let replies = vec![Envelope::stream_item(request.id, b"0".to_vec()),
+                           Envelope::stream_item(request.id, b"1".to_vec()),
+                           Envelope::stream_end(request.id, Ok(()))];
+        for reply in replies.into_iter() {
+            let frames = reply.encode(route.clone());
+            wire::send_frames(&mut server, frames.as_slice()).unwrap();
+            channel.process().unwrap();
+        }
+        let (route, request) = requests.remove(0).unwrap();
+        let reply = Envelope::stream_end(request.id, Err(Error::new(NotFound)));
+        wire::send_frames(&mut server, reply.encode(route).as_slice())
+            .unwrap();
+        channel.process().unwrap();
+        assert_eq!(channel.pending(), 0);
+
+        values.map(proc(values) {
+            assert_eq!(values.ok().unwrap(), vec![0, 1]);
+        });
+        failed.map(proc(failed) {
+            assert_eq!(failed.err().unwrap().code(), NotFound);
+        });
+    }
+
+    #[test]
+    fn test_stream_cancel() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-stream-cancel-test");
+
+        let stream = channel.call_stream::<(), int>("forever", &());
+        assert_eq!(channel.pending(), 1);
+        stream.cancel();
+        assert_eq!(channel.pending(), 0);
+        channel.handle_events(zmq::POLLOUT).unwrap();
+
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, request) = Envelope::decode(frames).ok().unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, cancel) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(cancel.kind, Cancel);
+        assert_eq!(cancel.id, request.id);
+    }
+
+    #[test]
+    fn test_stream_decode_failure() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-stream-decode-test");
+        channel.set_circuit_breaker(Some(Default::default()));
+
+        let values = channel.call_stream::<(), int>("garbled", &()).collect();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (route, request) = Envelope::decode(frames).ok().unwrap();
+        let item = Envelope::stream_item(request.id, b"{".to_vec());
+        wire::send_frames(&mut server, item.encode(route).as_slice())
+            .unwrap();
+        channel.process().unwrap();
+        assert_eq!(channel.pending(), 0);
+        values.map(proc(values) {
+            assert_eq!(values.err().unwrap().code(), InternalServerError);
+        });
+        assert_eq!(channel.circuit_breaker().unwrap()
+                       .failures(InternalServerError), 1);
+
+        channel.handle_events(zmq::POLLOUT).unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, cancel) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(cancel.kind, Cancel);
+        assert_eq!(cancel.id, request.id);
+    }
+
+    #[test]
+    fn test_client_stream() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-sink-test");
+
+        let (mut sink, sum) =
+            channel.call_client_stream::<int, int>("sum");
+        sink.send(&1).unwrap();
+        sink.send(&2).unwrap();
+        sink.close();
+        channel.handle_events(zmq::POLLOUT).unwrap();
+
+        let mut received = Vec::new();
+        for _ in range(0u, 4) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            received.push(Envelope::decode(frames).ok().unwrap());
+        }
+        let kinds: Vec<_> = received.iter()
+            .map(|&(_, ref envelope)| envelope.kind.clone()).collect();
+        assert_eq!(kinds, vec![StreamRequest, StreamItem, StreamItem,
+                               StreamEnd]);
+        let (route, request) = received.remove(0).unwrap();
+        assert!(received.iter().all(|&(_, ref item)| item.id == request.id));
+        let payloads: Vec<_> = received.iter()
+            .map(|&(_, ref item)| item.payload.clone()).collect();
+        assert_eq!(payloads, vec![b"1".to_vec(), b"2".to_vec(), Vec::new()]);
+
+        let reply = Envelope::reply(request.id, Ok(b"3".to_vec()));
+        wire::send_frames(&mut server, reply.encode(route).as_slice())
+            .unwrap();
+        channel.process().unwrap();
+        assert_eq!(channel.pending(), 0);
+        sum.map(proc(sum) assert_eq!(sum.ok().unwrap(), 3));
+
+        let (mut sink, sum) = channel.call_client_stream::<int, int>("sum");
+        sum.cancel();
+        assert_eq!(sink.send(&1).err().unwrap().code(), Cancelled);
+    }
+
+    #[test]
+    fn test_stream_credits() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-stream-credit-test");
+
+        let _values = channel.call_stream::<int, int>("count", &10).collect();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (route, request) = Envelope::decode(frames).ok().unwrap();
+        for i in range(0, INITIAL_CREDITS / 2) {
+            let item = Envelope::stream_item(request.id,
+                                             i.to_string().into_bytes());
+            wire::send_frames(&mut server, item.encode(route.clone())
+                                               .as_slice()).unwrap();
+            channel.process().unwrap();
+        }
+
+        // Taking half of the window grants it back.
+        assert_eq!(channel.send_queue().len(), 1);
+        channel.handle_events(zmq::POLLOUT).unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, credit) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(credit.kind, Credit);
+        assert_eq!(credit.id, request.id);
+        assert_eq!(credit.credits().ok(), Some(INITIAL_CREDITS / 2));
+    }
+
+    #[test]
+    fn test_request_credits() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-request-credit-test");
+
+        let (mut sink, _sum) = channel.call_client_stream::<int, int>("sum");
+        for i in range(0, INITIAL_CREDITS) {
+            sink.send(&(i as int)).unwrap();
+        }
+        assert_eq!(sink.credits(), 0);
+        assert_eq!(sink.send(&0).err().unwrap().code(), ResourceExhausted);
+        sink.pipe(range(0i, 2));
+        channel.handle_events(zmq::POLLOUT).unwrap();
+
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (route, request) = Envelope::decode(frames).ok().unwrap();
+        for _ in range(0, INITIAL_CREDITS) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            let (_, item) = Envelope::decode(frames).ok().unwrap();
+            assert_eq!(item.kind, StreamItem);
+        }
+        // The piped requests wait for credits.
+        {
+            let mut items = [server.as_poll_item(zmq::POLLIN)];
+            assert_eq!(zmq::poll(&mut items, 0).unwrap(), 0);
+        }
+
+        let credit = Envelope::credit(request.id, 4);
+        wire::send_frames(&mut server, credit.encode(route).as_slice())
+            .unwrap();
+        channel.process().unwrap();
+        let mut kinds = Vec::new();
+        for _ in range(0u, 3) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            let (_, message) = Envelope::decode(frames).ok().unwrap();
+            kinds.push(message.kind);
+        }
+        assert_eq!(kinds, vec![StreamItem, StreamItem, StreamEnd]);
+    }
+
+    #[test]
+    fn test_stream_expiry() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-stream-expiry-test");
+
+        let values = channel
+            .deadline(Deadline::after(Duration::milliseconds(5)))
+            .call_stream::<(), int>("forever", &())
+            .collect();
+        timer::sleep(Duration::milliseconds(10));
+        assert_eq!(channel.expire(), 1);
+        assert_eq!(channel.pending(), 0);
+        values.map(proc(values) {
+            assert_eq!(values.err().unwrap().code(), DeadlineExceeded);
+        });
+
+        // The endpoint is told to stop streaming.
+        channel.handle_events(zmq::POLLOUT).unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, request) = Envelope::decode(frames).ok().unwrap();
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, cancel) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(cancel.kind, Cancel);
+        assert_eq!(cancel.id, request.id);
+    }
+
+    #[test]
+    fn test_sync_timeout() {
+        let mut ctx = zmq::Context::new();
+        let (_server, mut channel) = connected_channel(
+            &mut ctx, "inproc://zuffy-client-sync-timeout-test");
+
+        // The server never replies, so the call expires on its own.
+        let reply: Result<String, Error> = channel.call("never", &())
+            .sync_timeout(Duration::milliseconds(5))
+            .ok().unwrap();
+        assert_eq!(reply.err().unwrap().code(), DeadlineExceeded);
+        assert_eq!(channel.pending(), 0);
+    }
+
+    #[test]
+    fn test_reactor_expiry() {
+        let mut ctx = zmq::Context::new();
+        let mut server = ctx.socket(zmq::ROUTER).unwrap();
+        server.bind("inproc://zuffy-client-expiry-test").unwrap();
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-client-expiry-test").unwrap();
+        let channel = RefCell::new(Channel::new(socket, JsonCodec));
+
+        let reply: AsyncFuture<Result<String, Error>> = channel.borrow_mut()
+            .deadline(Deadline::after(Duration::milliseconds(5)))
+            .call("never", &())
+            .async();
+        assert!(channel.borrow().next_expiry().is_some());
+
+        let mut reactor = Reactor::new();
+        reactor.push_item(
+            box |&: events: i16| channel.borrow().poll_item(events),
+            box |&mut:| { channel.borrow_mut().process().unwrap(); });
+        Channel::register_expiry(&channel, &mut reactor);
+        while !reply.ready() {
+            reactor.poll().unwrap();
+        }
+        assert_eq!(channel.borrow().pending(), 0);
+        reply.map(proc(reply) {
+            assert_eq!(reply.err().unwrap().code(), DeadlineExceeded);
+        });
+    }
+
+    #[test]
+    fn test_dead_endpoint() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-heartbeat-test");
+        channel.set_liveness(Some(
+            Liveness::new(Duration::milliseconds(1), 2)));
+
+        let reply: AsyncFuture<Result<String, Error>> =
+            channel.call("ignored", &()).async();
+        channel.heartbeat().unwrap();
+        assert!(channel.alive());
+        assert_eq!(channel.pending(), 1);
+
+        // The server never answers.
+        timer::sleep(Duration::milliseconds(5));
+        channel.heartbeat().unwrap();
+        assert!(!channel.alive());
+        assert_eq!(channel.pending(), 0);
+        reply.map(proc(reply) {
+            assert_eq!(reply.err().unwrap().code(), NetworkError);
+        });
+
+        // Until it echoes a heartbeat.
+        let mut kinds = Vec::new();
+        for _ in range(0u, 3) {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            let (route, envelope) = Envelope::decode(frames).ok().unwrap();
+            kinds.push(envelope.kind);
+            if envelope.kind == Heartbeat {
+                let frames = envelope.encode(route);
+                wire::send_frames(&mut server, frames.as_slice()).unwrap();
+            }
+        }
+        assert_eq!(kinds, vec![Request, Heartbeat, Heartbeat]);
+        channel.process().unwrap();
+        assert!(channel.alive());
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mut ctx = zmq::Context::new();
+        let mut server = ctx.socket(zmq::ROUTER).unwrap();
+        server.bind("inproc://zuffy-client-reconnect-test").unwrap();
+        let mut channel = Channel::connect(
+            &mut ctx, "inproc://zuffy-client-reconnect-test", JsonCodec)
+            .unwrap();
+        channel.set_liveness(Some(
+            Liveness::new(Duration::milliseconds(1), 2)));
+        let reply: AsyncFuture<Result<String, Error>> =
+            channel.call("ignored", &()).async();
+        assert!(!channel.heartbeat_reconnect(&mut ctx).unwrap());
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (first_route, envelope) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(envelope.kind, Request);
+
+        // The server never answers, so the channel connects again, failing
+        // the call sent on the old connection.
+        timer::sleep(Duration::milliseconds(5));
+        assert!(channel.heartbeat_reconnect(&mut ctx).unwrap());
+        assert!(channel.alive());
+        assert_eq!(channel.pending(), 0);
+        reply.map(proc(reply) {
+            assert_eq!(reply.err().unwrap().code(), NetworkError);
+        });
+        // Heartbeats come from a new connection, once the last one sent on
+        // the old connection is out of the way.
+        loop {
+            let frames = wire::recv_frames(&mut server, 0).unwrap();
+            let (route, envelope) = Envelope::decode(frames).ok().unwrap();
+            assert_eq!(envelope.kind, Heartbeat);
+            if route != first_route { break; }
+        }
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-client-reconnect-test").unwrap();
+        let mut channel = Channel::new(socket, JsonCodec);
+        assert_eq!(channel.reconnect(&mut ctx).err().unwrap().code(),
+                   FailedPrecondition);
+    }
+
+    fn retrying_channel(ctx: &mut zmq::Context, address: &str)
+            -> (zmq::Socket, Channel<JsonCodec>) {
+        let (server, mut channel) = connected_channel(ctx, address);
+        let mut policy = RetryPolicy::new(
+            3, Duration::milliseconds(1), Duration::milliseconds(1));
+        policy.set_attempt_timeout(Some(Duration::milliseconds(5)));
+        channel.set_retry_policy(Some(policy));
+        (server, channel)
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            retrying_channel(&mut ctx, "inproc://zuffy-client-retry-test");
+        let reply: AsyncFuture<Result<String, Error>> =
+            channel.idempotent().call("flaky", &()).async();
+
+        // The first attempt gets lost.
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (_, first) = Envelope::decode(frames).ok().unwrap();
+        timer::sleep(Duration::milliseconds(10));
+        assert_eq!(channel.expire(), 1);
+        assert_eq!(channel.pending(), 1);
+
+        // Once done backing off, the call is sent again.
+        timer::sleep(Duration::milliseconds(5));
+        assert_eq!(channel.expire(), 0);
+        let frames = wire::recv_frames(&mut server, 0).unwrap();
+        let (route, second) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(second.id, first.id);
+        let reply_frames =
+            Envelope::reply(second.id, Ok(b"\"ok\"".to_vec())).encode(route);
+        wire::send_frames(&mut server, reply_frames.as_slice()).unwrap();
+        channel.process().unwrap();
+        reply.map(proc(reply) {
+            assert_eq!(reply.ok().unwrap().as_slice(), "ok");
+        });
+    }
+
+    #[test]
+    fn test_no_retry() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            retrying_channel(&mut ctx, "inproc://zuffy-client-no-retry-test");
+
+        // Calls which aren't idempotent are never retried.
+        let once: AsyncFuture<Result<String, Error>> = channel
+            .deadline(Deadline::after(Duration::milliseconds(5)))
+            .call("once", &())
+            .async();
+        // Nor are calls past their deadline.
+        let late: AsyncFuture<Result<String, Error>> = channel
+            .deadline(Deadline::after(Duration::milliseconds(5)))
+            .idempotent()
+            .call("late", &())
+            .async();
+        timer::sleep(Duration::milliseconds(10));
+        assert_eq!(channel.expire(), 2);
+        assert_eq!(channel.pending(), 0);
+        once.map(proc(once) {
+            assert_eq!(once.err().unwrap().code(), DeadlineExceeded);
+        });
+        late.map(proc(late) {
+            assert_eq!(late.err().unwrap().code(), DeadlineExceeded);
+        });
+        for _ in range(0u, 2) {
+            wire::recv_frames(&mut server, 0).unwrap();
+        }
+        let mut items = [server.as_poll_item(zmq::POLLIN)];
+        assert_eq!(zmq::poll(&mut items, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut ctx = zmq::Context::new();
+        let (mut server, mut channel) =
+            connected_channel(&mut ctx, "inproc://zuffy-client-circuit-test");
+        let mut breaker = CircuitBreaker::new(2, 0.5, Duration::seconds(60));
+        breaker.set_min_calls(1);
+        channel.set_circuit_breaker(Some(breaker));
+
+        // The server doesn't answer, which trips the breaker.
+        let first: AsyncFuture<Result<String, Error>> = channel
+            .deadline(Deadline::after(Duration::milliseconds(1)))
+            .call("slow", &())
+            .async();
+        timer::sleep(Duration::milliseconds(5));
+        channel.expire();
+        first.map(proc(first) {
+            assert_eq!(first.err().unwrap().code(), DeadlineExceeded);
+        });
+        assert_eq!(channel.circuit_breaker().unwrap().state(), Open);
+
+        // Further calls fail without being sent.
+        let second: AsyncFuture<Result<String, Error>> =
+            channel.call("slow", &()).async();
+        second.map(proc(second) {
+            assert_eq!(second.err().unwrap().code(), Unavailable);
+        });
+        assert_eq!(channel.pending(), 0);
+        assert_eq!(channel.circuit_breaker().unwrap().rejected(), 1);
+        wire::recv_frames(&mut server, 0).unwrap();
+        let mut items = [server.as_poll_item(zmq::POLLIN)];
+        assert_eq!(zmq::poll(&mut items, 0).unwrap(), 0);
+    }
+}
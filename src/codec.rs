@@ -0,0 +1,644 @@
+use error::{Error, InternalServerError};
+use serialize::{json, Decodable, Decoder, Encodable, Encoder};
+use std::char;
+use std::io::IoError;
+use std::mem;
+use std::str;
+
+/// Converts messages of type `T` to and from payload frames.
+pub trait Codec<T> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Error>;
+    fn decode(&self, payload: &[u8]) -> Result<T, Error>;
+}
+
+/// Encodes `value` with `codec`; useful when `C` implements `Codec` for more
+/// than one type and method resolution would be ambiguous.
+pub fn encode<T, C: Codec<T>>(codec: &C, value: &T) -> Result<Vec<u8>, Error> {
+    codec.encode(value)
+}
+
+/// Counterpart to `encode`.
+pub fn decode<T, C: Codec<T>>(codec: &C, payload: &[u8]) -> Result<T, Error> {
+    codec.decode(payload)
+}
+
+
+/// Human readable encoding using `serialize::json`.
+#[deriving(Clone, Show)]
+pub struct JsonCodec;
+
+impl<'e, T> Codec<T> for JsonCodec
+        where T: Encodable<json::Encoder<'e>, IoError>
+               + Decodable<json::Decoder, json::DecoderError> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Error> {
+        Ok(json::encode(value).into_bytes())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, Error> {
+        let text = match str::from_utf8(payload) {
+            Some(text) => text,
+            None => return Err(Error::with_desc(
+                InternalServerError, "json: payload is not valid utf-8")),
+        };
+        json::decode(text).map_err(|err| {
+            Error::with_lazy_desc(InternalServerError,
+                                  proc() format!("json: {}", err))
+        })
+    }
+}
+
+
+/// Compact binary encoding.
+///
+/// Integers are written as LEB128 varints (zig-zag encoded when signed),
+/// floats as their little-endian IEEE 754 representation and strings,
+/// sequences and maps are prefixed by their length. Field names are not
+/// written, so both ends must agree on the exact message definition.
+#[deriving(Clone, Show)]
+pub struct BinaryCodec;
+
+impl<T> Codec<T> for BinaryCodec
+        where T: Encodable<BinaryEncoder, Error>
+               + Decodable<BinaryDecoder, Error> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut encoder = BinaryEncoder::new();
+        try!(value.encode(&mut encoder));
+        Ok(encoder.into_bytes())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<T, Error> {
+        let mut decoder = BinaryDecoder::new(payload.to_vec());
+        let value = try!(Decodable::decode(&mut decoder));
+        if !decoder.done() {
+            return Err(decoder.error("trailing bytes after message"));
+        }
+        Ok(value)
+    }
+}
+
+pub type EncodeResult = Result<(), Error>;
+pub type DecodeResult<T> = Result<T, Error>;
+
+pub struct BinaryEncoder {
+    output: Vec<u8>,
+}
+
+impl BinaryEncoder {
+    pub fn new() -> BinaryEncoder {
+        BinaryEncoder { output: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> { self.output }
+
+    fn write_varint(&mut self, mut value: u64) -> EncodeResult {
+        while value >= 0x80 {
+            self.output.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.output.push(value as u8);
+        Ok(())
+    }
+
+    fn write_signed(&mut self, value: i64) -> EncodeResult {
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    fn write_fixed(&mut self, mut value: u64, bytes: uint) -> EncodeResult {
+        for _ in range(0, bytes) {
+            self.output.push(value as u8);
+            value >>= 8;
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<Error> for BinaryEncoder {
+    fn emit_nil(&mut self) -> EncodeResult { Ok(()) }
+
+    fn emit_uint(&mut self, v: uint) -> EncodeResult {
+        self.write_varint(v as u64)
+    }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult { self.write_varint(v) }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult {
+        self.write_varint(v as u64)
+    }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult {
+        self.write_varint(v as u64)
+    }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult {
+        self.output.push(v);
+        Ok(())
+    }
+
+    fn emit_int(&mut self, v: int) -> EncodeResult {
+        self.write_signed(v as i64)
+    }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult { self.write_signed(v) }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult {
+        self.write_signed(v as i64)
+    }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult {
+        self.write_signed(v as i64)
+    }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult {
+        self.output.push(v as u8);
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, v: bool) -> EncodeResult {
+        self.output.push(if v { 1 } else { 0 });
+        Ok(())
+    }
+
+    fn emit_f64(&mut self, v: f64) -> EncodeResult {
+        self.write_fixed(unsafe { mem::transmute::<f64, u64>(v) }, 8)
+    }
+    fn emit_f32(&mut self, v: f32) -> EncodeResult {
+        self.write_fixed(unsafe { mem::transmute::<f32, u32>(v) } as u64, 4)
+    }
+
+    fn emit_char(&mut self, v: char) -> EncodeResult {
+        self.write_varint(v as u64)
+    }
+    fn emit_str(&mut self, v: &str) -> EncodeResult {
+        try!(self.write_varint(v.len() as u64));
+        self.output.push_all(v.as_bytes());
+        Ok(())
+    }
+
+    fn emit_enum(&mut self, _name: &str,
+                 f: |&mut BinaryEncoder| -> EncodeResult) -> EncodeResult {
+        f(self)
+    }
+    fn emit_enum_variant(&mut self, _v_name: &str, v_id: uint, _len: uint,
+                         f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        try!(self.write_varint(v_id as u64));
+        f(self)
+    }
+    fn emit_enum_variant_arg(&mut self, _a_idx: uint,
+                             f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+    fn emit_enum_struct_variant(&mut self, v_name: &str, v_id: uint,
+                                len: uint,
+                                f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        self.emit_enum_variant(v_name, v_id, len, f)
+    }
+    fn emit_enum_struct_variant_field(
+            &mut self, _f_name: &str, f_idx: uint,
+            f: |&mut BinaryEncoder| -> EncodeResult) -> EncodeResult {
+        self.emit_enum_variant_arg(f_idx, f)
+    }
+
+    fn emit_struct(&mut self, _name: &str, _len: uint,
+                   f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+    fn emit_struct_field(&mut self, _f_name: &str, _f_idx: uint,
+                         f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_tuple(&mut self, len: uint,
+                  f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        try!(self.write_varint(len as u64));
+        f(self)
+    }
+    fn emit_tuple_arg(&mut self, _idx: uint,
+                      f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+    fn emit_tuple_struct(&mut self, _name: &str, len: uint,
+                         f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        self.emit_tuple(len, f)
+    }
+    fn emit_tuple_struct_arg(&mut self, f_idx: uint,
+                             f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        self.emit_tuple_arg(f_idx, f)
+    }
+
+    fn emit_option(&mut self, f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+    fn emit_option_none(&mut self) -> EncodeResult {
+        self.output.push(0);
+        Ok(())
+    }
+    fn emit_option_some(&mut self,
+                        f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        self.output.push(1);
+        f(self)
+    }
+
+    fn emit_seq(&mut self, len: uint,
+                f: |&mut BinaryEncoder| -> EncodeResult) -> EncodeResult {
+        try!(self.write_varint(len as u64));
+        f(self)
+    }
+    fn emit_seq_elt(&mut self, _idx: uint,
+                    f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_map(&mut self, len: uint,
+                f: |&mut BinaryEncoder| -> EncodeResult) -> EncodeResult {
+        try!(self.write_varint(len as u64));
+        f(self)
+    }
+    fn emit_map_elt_key(&mut self, _idx: uint,
+                        f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+    fn emit_map_elt_val(&mut self, _idx: uint,
+                        f: |&mut BinaryEncoder| -> EncodeResult)
+            -> EncodeResult {
+        f(self)
+    }
+}
+
+
+pub struct BinaryDecoder {
+    input: Vec<u8>,
+    position: uint,
+}
+
+impl BinaryDecoder {
+    pub fn new(input: Vec<u8>) -> BinaryDecoder {
+        BinaryDecoder {
+            input: input,
+            position: 0,
+        }
+    }
+
+    pub fn done(&self) -> bool { self.remaining() == 0 }
+
+    fn remaining(&self) -> uint { self.input.len() - self.position }
+
+    fn read_byte(&mut self) -> DecodeResult<u8> {
+        if self.remaining() == 0 {
+            return Err(self.error("unexpected end of message"));
+        }
+        self.position += 1;
+        Ok(self.input[self.position - 1])
+    }
+
+    fn read_bytes(&mut self, len: uint) -> DecodeResult<&[u8]> {
+        if len > self.remaining() {
+            return Err(self.error("unexpected end of message"));
+        }
+        self.position += len;
+        Ok(self.input[self.position - len..self.position])
+    }
+
+    fn read_varint(&mut self) -> DecodeResult<u64> {
+        let mut value = 0u64;
+        let mut shift = 0u;
+        loop {
+            let byte = try!(self.read_byte());
+            // The tenth byte only holds the top bit of a u64.
+            if shift >= 64 || (shift == 63 && byte & 0x7f > 1) {
+                return Err(self.error("varint overflow"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_signed(&mut self) -> DecodeResult<i64> {
+        let value = try!(self.read_varint());
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    fn read_bounded(&mut self, max: u64) -> DecodeResult<u64> {
+        let value = try!(self.read_varint());
+        if value > max {
+            return Err(self.error("integer out of range"));
+        }
+        Ok(value)
+    }
+
+    fn read_bounded_signed(&mut self, min: i64, max: i64)
+            -> DecodeResult<i64> {
+        let value = try!(self.read_signed());
+        if value < min || value > max {
+            return Err(self.error("integer out of range"));
+        }
+        Ok(value)
+    }
+
+    fn read_fixed(&mut self, bytes: uint) -> DecodeResult<u64> {
+        let mut value = 0u64;
+        for index in range(0, bytes) {
+            value |= (try!(self.read_byte()) as u64) << (index * 8);
+        }
+        Ok(value)
+    }
+
+    fn read_len(&mut self) -> DecodeResult<uint> {
+        let len = try!(self.read_varint());
+        if len > self.remaining() as u64 {
+            // Every element takes up at least one byte, except for nils which
+            // nobody sends in bulk.
+            return Err(self.error("length exceeds message size"));
+        }
+        Ok(len as uint)
+    }
+}
+
+impl Decoder<Error> for BinaryDecoder {
+    fn read_nil(&mut self) -> DecodeResult<()> { Ok(()) }
+
+    fn read_uint(&mut self) -> DecodeResult<uint> {
+        self.read_bounded(::std::uint::MAX as u64).map(|v| v as uint)
+    }
+    fn read_u64(&mut self) -> DecodeResult<u64> { self.read_varint() }
+    fn read_u32(&mut self) -> DecodeResult<u32> {
+        self.read_bounded(::std::u32::MAX as u64).map(|v| v as u32)
+    }
+    fn read_u16(&mut self) -> DecodeResult<u16> {
+        self.read_bounded(::std::u16::MAX as u64).map(|v| v as u16)
+    }
+    fn read_u8(&mut self) -> DecodeResult<u8> { self.read_byte() }
+
+    fn read_int(&mut self) -> DecodeResult<int> {
+        self.read_bounded_signed(::std::int::MIN as i64,
+                                 ::std::int::MAX as i64).map(|v| v as int)
+    }
+    fn read_i64(&mut self) -> DecodeResult<i64> { self.read_signed() }
+    fn read_i32(&mut self) -> DecodeResult<i32> {
+        self.read_bounded_signed(::std::i32::MIN as i64,
+                                 ::std::i32::MAX as i64).map(|v| v as i32)
+    }
+    fn read_i16(&mut self) -> DecodeResult<i16> {
+        self.read_bounded_signed(::std::i16::MIN as i64,
+                                 ::std::i16::MAX as i64).map(|v| v as i16)
+    }
+    fn read_i8(&mut self) -> DecodeResult<i8> {
+        self.read_byte().map(|v| v as i8)
+    }
+
+    fn read_bool(&mut self) -> DecodeResult<bool> {
+        match try!(self.read_byte()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.error("invalid bool")),
+        }
+    }
+
+    fn read_f64(&mut self) -> DecodeResult<f64> {
+        let bits = try!(self.read_fixed(8));
+        Ok(unsafe { mem::transmute::<u64, f64>(bits) })
+    }
+    fn read_f32(&mut self) -> DecodeResult<f32> {
+        let bits = try!(self.read_fixed(4)) as u32;
+        Ok(unsafe { mem::transmute::<u32, f32>(bits) })
+    }
+
+    fn read_char(&mut self) -> DecodeResult<char> {
+        let value = try!(self.read_bounded(::std::u32::MAX as u64));
+        match char::from_u32(value as u32) {
+            Some(c) => Ok(c),
+            None => Err(self.error("invalid char")),
+        }
+    }
+    fn read_str(&mut self) -> DecodeResult<String> {
+        let len = try!(self.read_len());
+        let bytes = try!(self.read_bytes(len)).to_vec();
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(self.error("string is not valid utf-8")),
+        }
+    }
+
+    fn read_enum<T>(&mut self, _name: &str,
+                    f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+    fn read_enum_variant<T>(&mut self, names: &[&str],
+                            f: |&mut BinaryDecoder, uint|
+                                -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        let v_id = try!(self.read_varint());
+        if v_id >= names.len() as u64 {
+            return Err(self.error("invalid enum variant"));
+        }
+        f(self, v_id as uint)
+    }
+    fn read_enum_variant_arg<T>(&mut self, _a_idx: uint,
+                                f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+    fn read_enum_struct_variant<T>(&mut self, names: &[&str],
+                                   f: |&mut BinaryDecoder, uint|
+                                       -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        self.read_enum_variant(names, f)
+    }
+    fn read_enum_struct_variant_field<T>(
+            &mut self, _f_name: &str, f_idx: uint,
+            f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        self.read_enum_variant_arg(f_idx, f)
+    }
+
+    fn read_struct<T>(&mut self, _s_name: &str, _len: uint,
+                      f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+    fn read_struct_field<T>(&mut self, _f_name: &str, _f_idx: uint,
+                            f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_tuple<T>(&mut self,
+                     f: |&mut BinaryDecoder, uint| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        let len = try!(self.read_len());
+        f(self, len)
+    }
+    fn read_tuple_arg<T>(&mut self, _a_idx: uint,
+                         f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+    fn read_tuple_struct<T>(&mut self, _s_name: &str,
+                            f: |&mut BinaryDecoder, uint|
+                                -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        self.read_tuple(f)
+    }
+    fn read_tuple_struct_arg<T>(&mut self, a_idx: uint,
+                                f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        self.read_tuple_arg(a_idx, f)
+    }
+
+    fn read_option<T>(&mut self,
+                      f: |&mut BinaryDecoder, bool| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        let present = try!(self.read_bool());
+        f(self, present)
+    }
+
+    fn read_seq<T>(&mut self,
+                   f: |&mut BinaryDecoder, uint| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        let len = try!(self.read_len());
+        f(self, len)
+    }
+    fn read_seq_elt<T>(&mut self, _idx: uint,
+                       f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn read_map<T>(&mut self,
+                   f: |&mut BinaryDecoder, uint| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        let len = try!(self.read_len());
+        f(self, len)
+    }
+    fn read_map_elt_key<T>(&mut self, _idx: uint,
+                           f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+    fn read_map_elt_val<T>(&mut self, _idx: uint,
+                           f: |&mut BinaryDecoder| -> DecodeResult<T>)
+            -> DecodeResult<T> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> Error {
+        let err = err.to_string();
+        Error::with_lazy_desc(InternalServerError,
+                              proc() format!("binary: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Codec, BinaryCodec, JsonCodec};
+    use error::InternalServerError;
+    use std::collections::HashMap;
+
+    #[deriving(Encodable, Decodable, PartialEq, Show, Clone)]
+    enum Shape {
+        Circle(f64),
+        Polygon { sides: Vec<(i32, i32)> },
+        Empty,
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show, Clone)]
+    struct Message {
+        id: u64,
+        offset: i16,
+        name: String,
+        initial: char,
+        shape: Shape,
+        parent: Option<uint>,
+        tags: HashMap<String, bool>,
+        ratio: f32,
+    }
+
+    fn message() -> Message {
+        let mut tags = HashMap::new();
+        tags.insert("a".to_string(), true);
+        tags.insert("b".to_string(), false);
+        Message {
+            id: 1 << 40,
+            offset: -300,
+            name: "zuffy".to_string(),
+            initial: 'z',
+            shape: Polygon {
+                sides: vec![(0, 0), (-1, 5), (::std::i32::MAX, 2)],
+            },
+            parent: Some(12),
+            tags: tags,
+            ratio: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let payload = JsonCodec.encode(&message()).unwrap();
+        assert_eq!(JsonCodec.decode(payload.as_slice()).ok(),
+                   Some(message()));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        for shape in [Circle(-1.25), Empty].iter() {
+            let mut value = message();
+            value.shape = shape.clone();
+            value.parent = None;
+            let payload = BinaryCodec.encode(&value).unwrap();
+            assert_eq!(BinaryCodec.decode(payload.as_slice()).ok(),
+                       Some(value));
+        }
+        let payload = BinaryCodec.encode(&message()).unwrap();
+        assert_eq!(BinaryCodec.decode(payload.as_slice()).ok(),
+                   Some(message()));
+    }
+
+    #[test]
+    fn test_binary_is_compact() {
+        let value = (1u32, -1i32, "ab".to_string());
+        let payload = BinaryCodec.encode(&value).unwrap();
+        assert_eq!(payload.as_slice(), [3, 1, 1, 2, b'a', b'b'].as_slice());
+    }
+
+    #[test]
+    fn test_binary_truncated() {
+        let payload = BinaryCodec.encode(&message()).unwrap();
+        let truncated = payload[..payload.len() - 1];
+        let result: Result<Message, _> = BinaryCodec.decode(truncated);
+        assert_eq!(result.err().unwrap().code(), InternalServerError);
+    }
+
+    #[test]
+    fn test_binary_trailing_bytes() {
+        let mut payload = BinaryCodec.encode(&5u8).unwrap();
+        payload.push(0);
+        let result: Result<u8, _> = BinaryCodec.decode(payload.as_slice());
+        assert_eq!(result.err().unwrap().desc(),
+                   "binary: trailing bytes after message");
+    }
+
+    #[test]
+    fn test_binary_varint_overflow() {
+        let mut payload = BinaryCodec.encode(&::std::u64::MAX).unwrap();
+        assert_eq!(payload.len(), 10);
+        assert_eq!(BinaryCodec.decode(payload.as_slice()).ok(),
+                   Some(::std::u64::MAX));
+        *payload.last_mut().unwrap() = 2;
+        let result: Result<u64, _> = BinaryCodec.decode(payload.as_slice());
+        assert_eq!(result.err().unwrap().desc(), "binary: varint overflow");
+    }
+
+    #[test]
+    fn test_json_invalid() {
+        let result: Result<Message, _> = JsonCodec.decode(b"{\"id\": 1}");
+        assert_eq!(result.err().unwrap().code(), InternalServerError);
+    }
+}
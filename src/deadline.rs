@@ -0,0 +1,147 @@
+use std::cmp;
+use std::comm::{Disconnected, Empty};
+use std::i64;
+use std::io::Timer;
+use std::num::Saturating;
+use std::time::Duration;
+use std::u64;
+use time;
+
+/// A point in time, on the monotonic clock, by which a call must complete.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
+pub struct Deadline {
+    at_ns: u64,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline::after_from(now_ns(), budget)
+    }
+
+    pub fn at_ns(at_ns: u64) -> Deadline {
+        Deadline { at_ns: at_ns }
+    }
+
+    pub fn as_ns(&self) -> u64 { self.at_ns }
+
+    /// Time left until the deadline, zero if it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.remaining_from(now_ns())
+    }
+
+    /// Time left until the deadline in milliseconds, rounded up, as used for
+    /// poll timeouts.
+    pub fn timeout_ms(&self) -> i64 {
+        let ns = self.remaining().num_nanoseconds().unwrap_or(i64::MAX);
+        ns / 1_000_000 + if ns % 1_000_000 == 0 { 0 } else { 1 }
+    }
+
+    pub fn expired(&self) -> bool {
+        now_ns() >= self.at_ns
+    }
+
+    /// The earlier of two optional deadlines.
+    pub fn earliest(a: Option<Deadline>, b: Option<Deadline>)
+            -> Option<Deadline> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (a, None) => a,
+            (None, b) => b,
+        }
+    }
+
+    fn after_from(now_ns: u64, budget: Duration) -> Deadline {
+        let budget_ns = match budget.num_nanoseconds() {
+            Some(ns) if ns > 0 => ns as u64,
+            Some(_) => 0,
+            None => if budget > Duration::zero() { u64::MAX } else { 0 },
+        };
+        Deadline { at_ns: now_ns.saturating_add(budget_ns) }
+    }
+
+    fn remaining_from(&self, now_ns: u64) -> Duration {
+        if now_ns >= self.at_ns {
+            Duration::zero()
+        } else {
+            Duration::nanoseconds((self.at_ns - now_ns) as i64)
+        }
+    }
+}
+
+pub fn now_ns() -> u64 { time::precise_time_ns() }
+
+/// Receives from `receiver`, waiting no later than `deadline`, if any.
+/// Returns `None` if the deadline passes first.
+pub fn recv_until<T: Send>(receiver: &Receiver<T>, deadline: Option<Deadline>)
+        -> Option<Result<T, ()>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Some(receiver.recv_opt()),
+    };
+    match receiver.try_recv() {
+        Ok(value) => return Some(Ok(value)),
+        Err(Disconnected) => return Some(Err(())),
+        Err(Empty) => {},
+    }
+    if deadline.expired() { return None; }
+    let mut timer = match Timer::new() {
+        Ok(timer) => timer,
+        // There is no telling when the deadline passes, so give up as if it
+        // had rather than risk waiting forever.
+        Err(err) => {
+            error!("deadline: cannot create a timer: {}", err);
+            return None;
+        },
+    };
+    let timeout = timer.oneshot(deadline.remaining());
+    select! {
+        value = receiver.recv_opt() => Some(value),
+        _ = timeout.recv_opt() => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Deadline, recv_until};
+    use std::time::Duration;
+
+    #[test]
+    fn test_remaining() {
+        let deadline = Deadline::after_from(1000, Duration::nanoseconds(500));
+        assert_eq!(deadline.as_ns(), 1500);
+        assert_eq!(deadline.remaining_from(1200), Duration::nanoseconds(300));
+        assert_eq!(deadline.remaining_from(1500), Duration::zero());
+        assert_eq!(deadline.remaining_from(2000), Duration::zero());
+    }
+
+    #[test]
+    fn test_negative_budget() {
+        let deadline = Deadline::after_from(1000, Duration::seconds(-1));
+        assert_eq!(deadline.as_ns(), 1000);
+        assert!(Deadline::after(Duration::seconds(-1)).expired());
+        assert!(!Deadline::after(Duration::seconds(60)).expired());
+    }
+
+    #[test]
+    fn test_earliest() {
+        let a = Some(Deadline::at_ns(5));
+        let b = Some(Deadline::at_ns(7));
+        assert_eq!(Deadline::earliest(a, b), a);
+        assert_eq!(Deadline::earliest(b, a), a);
+        assert_eq!(Deadline::earliest(None, b), b);
+        assert_eq!(Deadline::earliest(a, None), a);
+        assert_eq!(Deadline::earliest(None, None), None);
+    }
+
+    #[test]
+    fn test_recv_until() {
+        let (sender, receiver) = channel();
+        sender.send(1i);
+        assert_eq!(recv_until(&receiver, None), Some(Ok(1)));
+        let deadline = Deadline::after(Duration::milliseconds(5));
+        assert_eq!(recv_until(&receiver, Some(deadline)), None);
+        assert!(deadline.expired());
+        drop(sender);
+        assert_eq!(recv_until(&receiver, Some(deadline)), Some(Err(())));
+    }
+}
@@ -1,16 +1,115 @@
 use lazy::Lazy;
+use std::fmt;
 use std::str::{MaybeOwned, IntoMaybeOwned, Slice, Owned};
+use std::time::Duration;
+use std::u16;
+use zmq;
 
+/// What went wrong with a call. Codes shared with other RPC systems have the
+/// same numeric values, see `to_u32`, which never change.
 #[deriving(Eq, PartialEq, Show)]
 pub enum ErrorCode {
+    /// The caller cancelled the call.
+    Cancelled,
+    /// The request is malformed, regardless of the state of the service.
+    InvalidArgument,
+    /// The deadline passed before the call completed.
     DeadlineExceeded,
-    NetworkError,
+    /// Something the request refers to doesn't exist.
+    NotFound,
+    /// Something the request would create exists already.
+    AlreadyExists,
+    /// The caller may not make the call.
+    PermissionDenied,
+    /// Some quota or limit was hit, and the call may succeed later.
+    ResourceExhausted,
+    /// The service is not in a state in which the call can succeed.
+    FailedPrecondition,
+    /// The service doesn't implement the method.
+    Unimplemented,
+    /// A bug in the service.
     InternalServerError,
+    /// The service can't be reached for now, e.g. a circuit breaker is open.
+    Unavailable,
+    /// The caller is not authenticated.
+    Unauthenticated,
+    /// A message was lost or garbled, or the peer disappeared.
+    NetworkError,
+    /// A code defined by the application, see `APPLICATION_BASE`.
+    Application(u16),
+}
+
+/// Application-defined codes are sent as `APPLICATION_BASE + code`, well
+/// clear of the codes above.
+pub const APPLICATION_BASE: u32 = 1000;
+
+impl ErrorCode {
+    /// The stable numeric value of the code, used on the wire.
+    pub fn to_u32(&self) -> u32 {
+        match *self {
+            Cancelled => 1,
+            InvalidArgument => 3,
+            DeadlineExceeded => 4,
+            NotFound => 5,
+            AlreadyExists => 6,
+            PermissionDenied => 7,
+            ResourceExhausted => 8,
+            FailedPrecondition => 9,
+            Unimplemented => 12,
+            InternalServerError => 13,
+            Unavailable => 14,
+            Unauthenticated => 16,
+            NetworkError => 17,
+            Application(code) => APPLICATION_BASE + code as u32,
+        }
+    }
+
+    /// The code with the numeric value `value`, if there is one.
+    pub fn from_u32(value: u32) -> Option<ErrorCode> {
+        Some(match value {
+            1 => Cancelled,
+            3 => InvalidArgument,
+            4 => DeadlineExceeded,
+            5 => NotFound,
+            6 => AlreadyExists,
+            7 => PermissionDenied,
+            8 => ResourceExhausted,
+            9 => FailedPrecondition,
+            12 => Unimplemented,
+            13 => InternalServerError,
+            14 => Unavailable,
+            16 => Unauthenticated,
+            17 => NetworkError,
+            _ if value >= APPLICATION_BASE &&
+                 value - APPLICATION_BASE <= u16::MAX as u32 => {
+                Application((value - APPLICATION_BASE) as u16)
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Structured information about an error, meant for programs rather than
+/// people. Details are sent along with error replies.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Detail {
+    /// How long the caller should wait before trying again.
+    RetryAfter(Duration),
+    /// A field of the request, and what is wrong with it.
+    FieldViolation(String, String),
+    /// Anything else, as a key and a value.
+    Info(String, String),
 }
 
+/// A failed call: a code, a description which may be computed lazily,
+/// structured details and the error which caused it, if any.
+///
+/// Formatting an error with `{}` prints the whole chain of causes.
 pub struct Error {
     code: ErrorCode,
     desc: Lazy<MaybeOwned<'static>>,
+    details: Vec<Detail>,
+    cause: Option<Box<Error>>,
 }
 impl Error {
     pub fn new(code: ErrorCode) -> Error {
@@ -21,7 +120,9 @@ impl Error {
             -> Error {
         Error {
             code: code,
-            desc: Lazy::from_value(desc.into_maybe_owned())
+            desc: Lazy::from_value(desc.into_maybe_owned()),
+            details: Vec::new(),
+            cause: None,
         }
     }
 
@@ -30,6 +131,8 @@ impl Error {
         Error {
             code: code,
             desc: Lazy::from_fn(proc() desc().into_maybe_owned()),
+            details: Vec::new(),
+            cause: None,
         }
     }
 
@@ -40,11 +143,152 @@ impl Error {
             &Owned(ref s) => s.as_slice(),
         }
     }
+
+    /// Converts the error of a socket call, with a code depending on what
+    /// went wrong; `NetworkError` unless more specific.
+    pub fn from_zmq(err: zmq::Error) -> Error {
+        Error::with_lazy_desc(zmq_code(err), proc() format!("zmq: {}", err))
+    }
+
+    /// Wraps `cause`, e.g. the error of the socket call which failed.
+    pub fn caused_by(mut self, cause: Error) -> Error {
+        self.cause = Some(box cause);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: Detail) -> Error {
+        self.details.push(detail);
+        self
+    }
+
+    pub fn cause(&self) -> Option<&Error> {
+        self.cause.as_ref().map(|cause| &**cause)
+    }
+
+    pub fn details(&self) -> &[Detail] { self.details.as_slice() }
+
+    /// The first `RetryAfter` detail.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.details.iter()
+            .filter_map(|detail| match *detail {
+                RetryAfter(delay) => Some(delay),
+                _ => None,
+            })
+            .next()
+    }
+}
+
+// Socket errors are about this side, not the request: transient shortages
+// leave the endpoint unreachable for now, and misuse of the socket API is a
+// bug here.
+fn zmq_code(err: zmq::Error) -> ErrorCode {
+    match err {
+        zmq::EAGAIN | zmq::ENOMEM | zmq::EMFILE | zmq::ENOBUFS |
+        zmq::ETERM | zmq::EHOSTUNREACH | zmq::ECONNREFUSED | zmq::ENOTCONN |
+        zmq::ENETDOWN => Unavailable,
+        zmq::EINVAL | zmq::EFAULT | zmq::ENOTSOCK | zmq::EMSGSIZE |
+        zmq::ENAMETOOLONG | zmq::EPROTONOSUPPORT | zmq::ENOTSUP |
+        zmq::ENOCOMPATPROTO => InternalServerError,
+        zmq::EADDRINUSE | zmq::EADDRNOTAVAIL | zmq::EFSM => FailedPrecondition,
+        zmq::EACCES => PermissionDenied,
+        zmq::ENOENT | zmq::ENODEV => NotFound,
+        _ => NetworkError,
+    }
+}
+
+impl fmt::Show for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut err = self;
+        loop {
+            try!(write!(f, "{}", err.code));
+            if !err.desc().is_empty() {
+                try!(write!(f, ": {}", err.desc()));
+            }
+            for detail in err.details.iter() {
+                try!(write!(f, " [{}]", detail));
+            }
+            err = match err.cause {
+                Some(ref cause) => &**cause,
+                None => return Ok(()),
+            };
+            try!(write!(f, "; caused by "));
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Error, InternalServerError, DeadlineExceeded};
+    use super::{Error, ErrorCode, InternalServerError, DeadlineExceeded};
+    use super::{Application, Cancelled, NetworkError, APPLICATION_BASE};
+    use super::{FieldViolation, RetryAfter, InvalidArgument};
+    use super::Unavailable;
+    use circuit::{CircuitBreaker, Open};
+    use retry::RetryPolicy;
+    use std::default::Default;
+    use std::time::Duration;
+    use zmq;
+
+    #[test]
+    fn test_code_values() {
+        for value in range(0u32, 20) {
+            match ErrorCode::from_u32(value) {
+                Some(code) => assert_eq!(code.to_u32(), value),
+                None => {},
+            }
+        }
+        assert_eq!(Cancelled.to_u32(), 1);
+        assert_eq!(DeadlineExceeded.to_u32(), 4);
+        assert_eq!(NetworkError.to_u32(), 17);
+        assert_eq!(ErrorCode::from_u32(2), None);
+        assert_eq!(ErrorCode::from_u32(APPLICATION_BASE), Some(Application(0)));
+        assert_eq!(Application(7).to_u32(), APPLICATION_BASE + 7);
+        assert_eq!(ErrorCode::from_u32(APPLICATION_BASE + 70000), None);
+    }
+
+    #[test]
+    fn test_chain() {
+        let err = Error::with_desc(InvalidArgument, "bad request")
+            .with_detail(FieldViolation("a".to_string(),
+                                        "must be positive".to_string()))
+            .caused_by(Error::with_desc(NetworkError, "boom")
+                       .with_detail(RetryAfter(Duration::seconds(1))));
+        assert_eq!(err.details().len(), 1);
+        assert_eq!(err.retry_after(), None);
+        assert_eq!(err.cause().unwrap().retry_after(),
+                   Some(Duration::seconds(1)));
+        assert!(err.cause().unwrap().cause().is_none());
+        assert_eq!(format!("{}", err).as_slice(),
+                   "InvalidArgument: bad request \
+                    [FieldViolation(a, must be positive)]; \
+                    caused by NetworkError: boom [RetryAfter(PT1S)]");
+        assert_eq!(format!("{}", Error::new(Cancelled)).as_slice(),
+                   "Cancelled");
+    }
+
+    #[test]
+    fn test_from_zmq() {
+        let err = Error::from_zmq(zmq::EAGAIN);
+        assert_eq!(err.code(), Unavailable);
+        assert!(err.desc().starts_with("zmq: "));
+        assert_eq!(Error::from_zmq(zmq::ENOMEM).code(), Unavailable);
+        assert_eq!(Error::from_zmq(zmq::ETERM).code(), Unavailable);
+        assert_eq!(Error::from_zmq(zmq::EINVAL).code(), InternalServerError);
+        assert_eq!(Error::from_zmq(zmq::EMSGSIZE).code(), InternalServerError);
+        assert_eq!(Error::from_zmq(zmq::EPROTO).code(), NetworkError);
+    }
+
+    #[test]
+    fn test_zmq_send_failure_retryable() {
+        // E.g. a send failing for lack of buffers.
+        let code = Error::from_zmq(zmq::EAGAIN).code();
+        let policy: RetryPolicy = Default::default();
+        assert!(policy.retryable(code));
+        let mut breaker: CircuitBreaker = Default::default();
+        breaker.set_min_calls(1);
+        assert!(breaker.allow());
+        breaker.record(Some(code));
+        assert_eq!(breaker.state(), Open);
+    }
 
     #[test]
     fn test_no_desc() {
@@ -0,0 +1,461 @@
+use deadline::{mod, Deadline};
+use error::{Error, Cancelled, DeadlineExceeded, FailedPrecondition};
+use error::InvalidArgument;
+use flow::{CreditWindow, INITIAL_CREDITS};
+use server::{Context, Dispatch, Incoming, Requests};
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+use wire::{mod, Envelope, Frames};
+use zmq;
+
+/// A decoded request, ready to be dispatched.
+pub struct Job {
+    route: Frames,
+    id: u64,
+    method: String,
+    payload: Vec<u8>,
+    ctx: Context,
+    requests: Option<Incoming>,
+    credits: Option<Receiver<u32>>,
+    // How long to wait for each streamed request, or for credits.
+    idle: Option<Duration>,
+    // Set when the executor can't run the call.
+    rejected: Option<Error>,
+}
+
+impl Job {
+    pub fn new(route: Frames, id: u64, method: String, payload: Vec<u8>,
+               ctx: Context) -> Job {
+        Job {
+            route: route,
+            id: id,
+            method: method,
+            payload: payload,
+            ctx: ctx,
+            requests: None,
+            credits: None,
+            idle: None,
+            rejected: None,
+        }
+    }
+
+    /// Hands the job the requests of a call taking a stream of them, waiting
+    /// no longer than `idle` for each, see `Requests`.
+    pub fn with_requests(mut self, requests: Incoming,
+                         idle: Option<Duration>) -> Job {
+        self.requests = Some(requests);
+        self.idle = idle;
+        self
+    }
+
+    /// Has the job wait for the credits granted by the client, as received
+    /// by the endpoint, whenever its streamed responses run out of them, see
+    /// `flow`. It waits no longer than `idle` at a time.
+    pub fn with_credits(mut self, credits: Receiver<u32>,
+                        idle: Option<Duration>) -> Job {
+        self.credits = Some(credits);
+        self.idle = idle;
+        self
+    }
+
+    // Has the job fail with `err` instead of being dispatched.
+    fn reject(mut self, err: Error) -> Job {
+        self.rejected = Some(err);
+        self
+    }
+
+    /// Dispatches the request, passing the frames of its reply to `emit`,
+    /// or those of every message of the stream for streaming methods, along
+    /// with the credits granted for streamed requests. Requests which
+    /// expired or were cancelled while waiting are not dispatched.
+    pub fn run<D: Dispatch>(self, dispatcher: &mut D, emit: |Frames|) {
+        let Job { route, id, method, payload, ctx, requests, credits, idle,
+                  rejected } = self;
+        let method = method.as_slice();
+        let emit = RefCell::new(emit);
+        let expired = ctx.deadline().map_or(false, |d| d.expired());
+        let ready = match rejected {
+            Some(err) => Err(err),
+            None if expired => Err(Error::with_desc(
+                DeadlineExceeded, "deadline exceeded before dispatch")),
+            None if ctx.cancelled() => Err(Error::with_desc(
+                Cancelled, "cancelled before dispatch")),
+            None => match (dispatcher.takes_stream(method), requests) {
+                (true, Some(incoming)) => Ok(Some(incoming)),
+                (false, None) => Ok(None),
+                (true, None) => Err(Error::with_desc(
+                    InvalidArgument, "method takes a stream of requests")),
+                (false, Some(_)) => Err(Error::with_desc(
+                    InvalidArgument, "method takes a single request")),
+            },
+        };
+        let grant = |credits: u32| {
+            (*emit.borrow_mut())(Envelope::credit(id, credits)
+                                     .encode(route.clone()))
+        };
+        if dispatcher.streaming(method) {
+            let mut window = CreditWindow::new(INITIAL_CREDITS);
+            let starved = Cell::new(false);
+            let result = {
+                let send_item = |item: Vec<u8>| {
+                    // Pauses the handler until the client grants more
+                    // credits, or the endpoint drops the call as it was
+                    // cancelled. Items which find no credits in time are
+                    // dropped, and the stream fails.
+                    if starved.get() { return; }
+                    match credits {
+                        Some(ref credits) => while !window.take() {
+                            let until = Deadline::earliest(
+                                ctx.deadline(), idle.map(Deadline::after));
+                            match deadline::recv_until(credits, until) {
+                                Some(Ok(granted)) => window.grant(granted),
+                                Some(Err(())) => break,
+                                None => {
+                                    starved.set(true);
+                                    return;
+                                },
+                            }
+                        },
+                        None => {},
+                    }
+                    (*emit.borrow_mut())(Envelope::stream_item(id, item)
+                                             .encode(route.clone()))
+                };
+                match ready {
+                    Ok(Some(incoming)) => {
+                        let mut requests = Requests::new(
+                            incoming, ctx.deadline(), idle, grant);
+                        dispatcher.dispatch_bidi(&ctx, method, &mut requests,
+                                                 send_item)
+                    },
+                    Ok(None) => dispatcher.dispatch_stream(
+                        &ctx, method, payload.as_slice(), send_item),
+                    Err(err) => Err(err),
+                }
+            };
+            let result = if starved.get() {
+                Err(Error::with_desc(DeadlineExceeded,
+                                     "no credits granted in time"))
+            } else {
+                result
+            };
+            (*emit.borrow_mut())(Envelope::stream_end(id, result)
+                                     .encode(route.clone()));
+        } else {
+            let result = match ready {
+                Ok(Some(incoming)) => {
+                    let mut requests = Requests::new(
+                        incoming, ctx.deadline(), idle, grant);
+                    dispatcher.dispatch_client_stream(&ctx, method,
+                                                      &mut requests)
+                },
+                Ok(None) => dispatcher.dispatch(&ctx, method,
+                                                payload.as_slice()),
+                Err(err) => Err(err),
+            };
+            (*emit.borrow_mut())(Envelope::reply(id, result)
+                                     .encode(route.clone()));
+        }
+    }
+}
+
+/// Decides where the requests received by a `server::Endpoint` run.
+pub trait Executor {
+    /// Starts running `job`, returning the replies it produced right away.
+    fn execute(&mut self, job: Job) -> Vec<Frames>;
+
+    /// Whether calls to `method` stream their responses, see
+    /// `Dispatch::streaming`. The endpoint keeps track of the credits of
+    /// those calls only.
+    fn streaming(&self, method: &str) -> bool;
+
+    /// An item, polled for `events`, which is readable while replies
+    /// produced elsewhere are waiting for `recv_reply`, or `None` if
+    /// `execute` returns all of them.
+    fn poll_item<'b>(&self, events: i16) -> Option<zmq::PollItem<'b>>;
+
+    /// Receives a single reply produced elsewhere.
+    fn recv_reply(&mut self) -> Result<Frames, Error>;
+}
+
+/// Runs requests on the calling thread, i.e. the reactor thread. Calls to
+/// methods streaming either their requests or their responses fail with
+/// `FailedPrecondition`: their handlers run for as long as the stream does,
+/// which would hold up every other call. Use a `ThreadPool` to serve them.
+pub struct Inline<D> {
+    dispatcher: D,
+}
+
+impl<D: Dispatch> Inline<D> {
+    pub fn new(dispatcher: D) -> Inline<D> {
+        Inline { dispatcher: dispatcher }
+    }
+
+    pub fn dispatcher(&mut self) -> &mut D { &mut self.dispatcher }
+}
+
+impl<D: Dispatch> Executor for Inline<D> {
+    fn execute(&mut self, job: Job) -> Vec<Frames> {
+        let streams = {
+            let method = job.method.as_slice();
+            self.dispatcher.streaming(method) ||
+                self.dispatcher.takes_stream(method)
+        };
+        let job = if streams {
+            job.reject(Error::with_desc(
+                FailedPrecondition, "streaming calls need a thread pool"))
+        } else {
+            job
+        };
+        let mut replies = Vec::new();
+        job.run(&mut self.dispatcher, |reply| replies.push(reply));
+        replies
+    }
+
+    fn streaming(&self, method: &str) -> bool {
+        self.dispatcher.streaming(method)
+    }
+
+    fn poll_item<'b>(&self, _events: i16) -> Option<zmq::PollItem<'b>> {
+        None
+    }
+
+    fn recv_reply(&mut self) -> Result<Frames, Error> {
+        Err(Error::with_desc(FailedPrecondition,
+                             "inline executor has no pending replies"))
+    }
+}
+
+/// Runs requests on a fixed number of worker threads, each with its own clone
+/// of the dispatcher. Replies come back over an inproc socket, which must be
+/// polled on the thread owning the endpoint.
+pub struct ThreadPool {
+    jobs: Sender<Job>,
+    replies: zmq::Socket,
+    // Tells streaming methods apart, see `Executor::streaming`.
+    dispatcher: Box<Dispatch + Send>,
+}
+
+impl ThreadPool {
+    pub fn new<D: Dispatch + Clone + Send>(ctx: &mut zmq::Context,
+                                           workers: uint,
+                                           dispatcher: D)
+            -> Result<ThreadPool, Error> {
+        assert!(workers > 0, "ThreadPool needs at least one worker.");
+        let address = format!("inproc://zuffy-thread-pool-{}",
+                              NEXT_POOL.fetch_add(1, SeqCst));
+        let mut replies = try!(ctx.socket(zmq::PULL)
+                                  .map_err(Error::from_zmq));
+        try!(replies.bind(address.as_slice()).map_err(Error::from_zmq));
+
+        let (jobs, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in range(0, workers) {
+            let mut sender = try!(ctx.socket(zmq::PUSH)
+                                     .map_err(Error::from_zmq));
+            try!(sender.connect(address.as_slice())
+                       .map_err(Error::from_zmq));
+            let receiver = receiver.clone();
+            let mut dispatcher = dispatcher.clone();
+            spawn(proc() {
+                loop {
+                    // Workers exit once the pool, and its sender, is gone.
+                    let job: Job = match receiver.lock().recv_opt() {
+                        Ok(job) => job,
+                        Err(()) => break,
+                    };
+                    job.run(&mut dispatcher, |reply| {
+                        match wire::send_frames(&mut sender, reply.as_slice()) {
+                            Ok(()) => {},
+                            Err(err) => error!("thread pool: {}", err),
+                        }
+                    });
+                }
+            });
+        }
+        Ok(ThreadPool {
+            jobs: jobs,
+            replies: replies,
+            dispatcher: box dispatcher as Box<Dispatch + Send>,
+        })
+    }
+}
+
+impl Executor for ThreadPool {
+    fn execute(&mut self, job: Job) -> Vec<Frames> {
+        self.jobs.send(job);
+        Vec::new()
+    }
+
+    fn streaming(&self, method: &str) -> bool {
+        self.dispatcher.streaming(method)
+    }
+
+    fn poll_item<'b>(&self, events: i16) -> Option<zmq::PollItem<'b>> {
+        Some(self.replies.as_poll_item(events))
+    }
+
+    fn recv_reply(&mut self) -> Result<Frames, Error> {
+        wire::recv_frames(&mut self.replies, 0)
+    }
+}
+
+static NEXT_POOL: AtomicUint = INIT_ATOMIC_UINT;
+
+#[cfg(test)]
+mod test {
+    use super::{Executor, Inline, Job, ThreadPool};
+    use error::{Error, DeadlineExceeded, FailedPrecondition};
+    use flow::INITIAL_CREDITS;
+    use server::{mod, Context, Dispatch};
+    use std::time::Duration;
+    use wire::{Envelope, Metadata, StreamEnd, StreamItem};
+    use zmq;
+
+    #[deriving(Clone)]
+    struct Reverse;
+    impl Dispatch for Reverse {
+        fn dispatch(&mut self, _ctx: &Context, method: &str, payload: &[u8])
+                -> Result<Vec<u8>, Error> {
+            if method == "reverse" {
+                Ok(payload.iter().rev().map(|&byte| byte).collect())
+            } else {
+                Err(server::unknown_method(method))
+            }
+        }
+
+        fn streaming(&self, method: &str) -> bool { method == "split" }
+
+        fn dispatch_stream(&mut self, _ctx: &Context, _method: &str,
+                           payload: &[u8], emit: |Vec<u8>|)
+                -> Result<(), Error> {
+            for &byte in payload.iter() {
+                emit(vec![byte]);
+            }
+            Ok(())
+        }
+    }
+
+    fn job(id: u64, payload: Vec<u8>) -> Job {
+        call(id, "reverse", payload)
+    }
+
+    fn call(id: u64, method: &str, payload: Vec<u8>) -> Job {
+        Job::new(vec![b"peer".to_vec()], id, method.to_string(), payload,
+                 Context::new(None, Metadata::new()))
+    }
+
+    #[test]
+    fn test_inline() {
+        let mut inline = Inline::new(Reverse);
+        assert!(inline.poll_item(zmq::POLLIN).is_none());
+        assert_eq!(inline.recv_reply().err().unwrap().code(),
+                   FailedPrecondition);
+        let reply = inline.execute(job(1, vec![1, 2, 3])).pop().unwrap();
+        let (route, reply) = Envelope::decode(reply).ok().unwrap();
+        assert_eq!(route, vec![b"peer".to_vec()]);
+        assert_eq!(reply.id, 1);
+        assert_eq!(reply.into_result().ok(), Some(vec![3, 2, 1]));
+    }
+
+    #[test]
+    fn test_thread_pool() {
+        let mut ctx = zmq::Context::new();
+        let mut pool = ThreadPool::new(&mut ctx, 2, Reverse).unwrap();
+        assert!(pool.poll_item(zmq::POLLIN).is_some());
+        for id in range(0u8, 4) {
+            assert!(pool.execute(job(id as u64, vec![id, 9])).is_empty());
+        }
+
+        let mut ids = Vec::new();
+        for _ in range(0u, 4) {
+            let (_, reply) =
+                Envelope::decode(pool.recv_reply().unwrap()).ok().unwrap();
+            let id = reply.id;
+            assert_eq!(reply.into_result().ok(), Some(vec![9, id as u8]));
+            ids.push(id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut inline = Inline::new(Reverse);
+        let mut replies = inline.execute(call(1, "split", vec![1, 2]));
+        assert_eq!(replies.len(), 1);
+        let (_, reply) =
+            Envelope::decode(replies.pop().unwrap()).ok().unwrap();
+        assert_eq!(reply.into_result().err().unwrap().code(),
+                   FailedPrecondition);
+
+        let mut ctx = zmq::Context::new();
+        let mut pool = ThreadPool::new(&mut ctx, 1, Reverse).unwrap();
+        assert!(pool.streaming("split"));
+        assert!(!pool.streaming("reverse"));
+        assert!(pool.execute(call(2, "split", vec![1, 2])).is_empty());
+        let replies: Vec<_> = range(0u, 3)
+            .map(|_| {
+                let reply = pool.recv_reply().unwrap();
+                let (_, reply) = Envelope::decode(reply).ok().unwrap();
+                reply
+            })
+            .collect();
+        assert_eq!(replies[0].kind, StreamItem);
+        assert_eq!(replies[0].payload, vec![1]);
+        assert_eq!(replies[1].kind, StreamItem);
+        assert_eq!(replies[1].payload, vec![2]);
+        assert_eq!(replies[2].kind, StreamEnd);
+    }
+
+    #[test]
+    fn test_credits() {
+        let mut ctx = zmq::Context::new();
+        let mut pool = ThreadPool::new(&mut ctx, 1, Reverse).unwrap();
+        let (grant, credits) = channel();
+        let payload = Vec::from_elem(INITIAL_CREDITS as uint + 2, 7u8);
+        pool.execute(call(1, "split", payload).with_credits(credits, None));
+        for _ in range(0, INITIAL_CREDITS) {
+            let (_, reply) =
+                Envelope::decode(pool.recv_reply().unwrap()).ok().unwrap();
+            assert_eq!(reply.kind, StreamItem);
+        }
+
+        // The handler waits for more credits.
+        {
+            let mut items = [pool.poll_item(zmq::POLLIN).unwrap()];
+            assert_eq!(zmq::poll(&mut items, 50).unwrap(), 0);
+        }
+        grant.send(2);
+        for kind in [StreamItem, StreamItem, StreamEnd].iter() {
+            let (_, reply) =
+                Envelope::decode(pool.recv_reply().unwrap()).ok().unwrap();
+            assert_eq!(reply.kind, *kind);
+        }
+    }
+
+    #[test]
+    fn test_credits_timeout() {
+        let mut ctx = zmq::Context::new();
+        let mut pool = ThreadPool::new(&mut ctx, 1, Reverse).unwrap();
+        let (_grant, credits) = channel();
+        let payload = Vec::from_elem(INITIAL_CREDITS as uint + 2, 7u8);
+        // The call has no deadline, but the wait for credits is bounded.
+        let job = call(1, "split", payload)
+            .with_credits(credits, Some(Duration::milliseconds(20)));
+        pool.execute(job);
+        for _ in range(0, INITIAL_CREDITS) {
+            let (_, reply) =
+                Envelope::decode(pool.recv_reply().unwrap()).ok().unwrap();
+            assert_eq!(reply.kind, StreamItem);
+        }
+
+        // The client never grants more credits.
+        let (_, reply) =
+            Envelope::decode(pool.recv_reply().unwrap()).ok().unwrap();
+        assert_eq!(reply.into_result().err().unwrap().code(),
+                   DeadlineExceeded);
+    }
+}
@@ -0,0 +1,86 @@
+use std::cmp;
+use std::u32;
+
+/// How many messages the sender of a stream may send before the receiver
+/// grants it more credits. Every stream starts with this many, in each
+/// direction.
+pub const INITIAL_CREDITS: u32 = 16;
+
+/// The sending side of a stream's flow control: how many more messages the
+/// receiver is willing to take.
+#[deriving(Clone, Show)]
+pub struct CreditWindow {
+    credits: u32,
+}
+
+impl CreditWindow {
+    pub fn new(credits: u32) -> CreditWindow {
+        CreditWindow { credits: credits }
+    }
+
+    pub fn credits(&self) -> u32 { self.credits }
+
+    /// Uses up a credit to send a message. Returns false, leaving the window
+    /// alone, if there are none left.
+    pub fn take(&mut self) -> bool {
+        if self.credits == 0 { return false; }
+        self.credits -= 1;
+        true
+    }
+
+    pub fn grant(&mut self, credits: u32) {
+        self.credits = cmp::min(self.credits as u64 + credits as u64,
+                                u32::MAX as u64) as u32;
+    }
+}
+
+/// The receiving side of a stream's flow control, which grants credits back
+/// as messages are consumed. Credits go out in batches of half the window, so
+/// the sender rarely runs dry while not every message costs a grant.
+#[deriving(Clone, Show)]
+pub struct Grants {
+    batch: u32,
+    consumed: u32,
+}
+
+impl Grants {
+    /// Grants for a sender which started with `window` credits.
+    pub fn new(window: u32) -> Grants {
+        Grants { batch: cmp::max(window / 2, 1), consumed: 0 }
+    }
+
+    /// Records a consumed message, returning the credits to grant if a batch
+    /// is due.
+    pub fn consumed(&mut self) -> Option<u32> {
+        self.consumed += 1;
+        if self.consumed < self.batch { return None; }
+        self.consumed = 0;
+        Some(self.batch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CreditWindow, Grants};
+
+    #[test]
+    fn test_window() {
+        let mut window = CreditWindow::new(2);
+        let mut grants = Grants::new(2);
+        assert!(window.take());
+        assert!(window.take());
+        assert!(!window.take());
+        assert_eq!(window.credits(), 0);
+
+        assert_eq!(grants.consumed(), Some(1));
+        window.grant(1);
+        assert!(window.take());
+        assert!(!window.take());
+
+        let mut grants = Grants::new(8);
+        let granted: Vec<Option<u32>> =
+            range(0u, 8).map(|_| grants.consumed()).collect();
+        assert_eq!(granted, vec![None, None, None, Some(4),
+                                 None, None, None, Some(4)]);
+    }
+}
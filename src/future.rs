@@ -1,9 +1,21 @@
+use deadline::Deadline;
+use error::{Error, DeadlineExceeded, InternalServerError};
 use movecell::MoveCell;
+use sendfuture::SendFuture;
+use std::cell::{Cell, RefCell};
+use std::mem;
 use std::rc::Rc;
+use std::time::Duration;
 
 pub trait Fulfiller<T> {
     fn sync(&mut self, promise: Promise<T>);
     fn async(&mut self, promise: Promise<T>);
+
+    /// Like `sync`, but gives up once `deadline` passes. The default ignores
+    /// the deadline, which suits fulfillers that never block.
+    fn sync_until(&mut self, promise: Promise<T>, _deadline: Deadline) {
+        self.sync(promise);
+    }
 }
 
 pub struct NoopFulfiller<T>;
@@ -12,32 +24,99 @@ impl<T> Fulfiller<T> for NoopFulfiller<T> {
     fn async(&mut self, _promise: Promise<T>) {}
 }
 
+/// Tells whoever fulfills a promise that nobody waits for its value anymore.
+/// Shared by a future, its promise and the futures derived from it with
+/// `map` and `then`.
+#[deriving(Clone)]
+pub struct CancelToken {
+    state: Rc<CancelState>,
+}
+
+struct CancelState {
+    cancelled: Cell<bool>,
+    callbacks: RefCell<Vec<proc():'static>>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken {
+            state: Rc::new(CancelState {
+                cancelled: Cell::new(false),
+                callbacks: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool { self.state.cancelled.get() }
+
+    /// Runs `callback` when the future is cancelled, or right away if it
+    /// already is.
+    pub fn on_cancel(&self, callback: proc():'static) {
+        if self.is_cancelled() {
+            callback();
+        } else {
+            self.state.callbacks.borrow_mut().push(callback);
+        }
+    }
+
+    /// Runs the callbacks registered with `on_cancel`, once.
+    pub fn cancel(&self) {
+        if self.is_cancelled() { return; }
+        self.state.cancelled.set(true);
+        let callbacks = mem::replace(&mut *self.state.callbacks.borrow_mut(),
+                                     Vec::new());
+        for callback in callbacks.into_iter() {
+            callback();
+        }
+    }
+
+    /// Cancels `tokens` whenever this one is.
+    fn forward(&self, tokens: Vec<CancelToken>) {
+        self.on_cancel(proc() {
+            for token in tokens.iter() {
+                token.cancel();
+            }
+        });
+    }
+}
+
 pub struct Promise<T> {
     state: StateRef<T>,
+    token: CancelToken,
 }
 
 impl<T> Clone for Promise<T> {
     fn clone(&self) -> Promise<T> {
-        Promise { state: self.state.clone() }
+        Promise { state: self.state.clone(), token: self.token.clone() }
     }
 }
 
 impl<T> Promise<T> {
     fn new() -> Promise<T> {
-        Promise { state: new_state() }
+        Promise::with_token(CancelToken::new())
+    }
+
+    fn with_token(token: CancelToken) -> Promise<T> {
+        Promise { state: new_state(), token: token }
     }
 
-    fn from_state(state: StateRef<T>) -> Promise<T> {
+    fn from_state(state: StateRef<T>, token: CancelToken) -> Promise<T> {
         Promise {
             state: state,
+            token: token,
         }
     }
 
     fn into_future(self) -> AsyncFuture<T> {
-        AsyncFuture { state: self.state }
+        AsyncFuture { state: self.state, token: self.token }
     }
 
+    /// The token through which the future's owner may cancel it.
+    pub fn cancel_token(&self) -> CancelToken { self.token.clone() }
+
+    /// Resolves the future; does nothing if it was cancelled.
     pub fn fulfill(self, value: T) -> () {
+        if self.token.is_cancelled() { return; }
         let state = self.state;
         match state.take() {
             None => { state.put(Ready(value)); },
@@ -51,6 +130,7 @@ impl<T> Promise<T> {
 pub struct Future<'a, T, F: Fulfiller<T> + 'a> {
     fulfiller: Option<&'a mut F>,
     state: StateRef<T>,
+    token: CancelToken,
 }
 
 impl<'a, T, F: Fulfiller<T>> Future<'a, T, F> {
@@ -58,6 +138,7 @@ impl<'a, T, F: Fulfiller<T>> Future<'a, T, F> {
         Future {
             fulfiller: Some(fulfiller),
             state: new_state(),
+            token: CancelToken::new(),
         }
     }
 
@@ -78,16 +159,44 @@ impl<'a, T, F: Fulfiller<T>> Future<'a, T, F> {
         }
     }
 
+    /// Like `sync`, but fails with `DeadlineExceeded` instead of blocking
+    /// for longer than `timeout`. Fails with `InternalServerError` right away
+    /// if nothing can produce the value synchronously, e.g. when the promise
+    /// was handed out by `new_with_promise`. The future is cancelled on
+    /// failure.
+    pub fn sync_timeout(self, timeout: Duration) -> Result<T, Error> {
+        let deadline = Deadline::after(timeout);
+        let promise = self.make_promise();
+        match self.fulfiller {
+            Some(f) => f.sync_until(promise, deadline),
+            _ => {}
+        }
+        match self.state.take() {
+            Some(Ready(value)) => Ok(value),
+            _ => {
+                self.token.cancel();
+                if deadline.expired() {
+                    Err(Error::with_desc(DeadlineExceeded,
+                                         "future not fulfilled in time"))
+                } else {
+                    Err(Error::with_desc(InternalServerError,
+                                         "future cannot be fulfilled \
+                                          synchronously"))
+                }
+            }
+        }
+    }
+
     pub fn async(self) -> AsyncFuture<T> {
         let promise = self.make_promise();
         match self.fulfiller {
             Some(f) => f.async(promise), _ => {}
         }
-        AsyncFuture { state: self.state }
+        AsyncFuture { state: self.state, token: self.token }
     }
 
     fn make_promise(&self) -> Promise<T> {
-        Promise::from_state(self.state.clone())
+        Promise::from_state(self.state.clone(), self.token.clone())
     }
 }
 
@@ -96,12 +205,17 @@ impl<T> Future<'static, T, NoopFulfiller<T>> {
         Future {
             fulfiller: None,
             state: Rc::new(MoveCell::from_value(Ready(value))),
+            token: CancelToken::new(),
         }
     }
 
     pub fn new_with_promise() ->
             (Future<'static, T, NoopFulfiller<T>>, Promise<T>) {
-        let op = Future { fulfiller: None, state: new_state() };
+        let op = Future {
+            fulfiller: None,
+            state: new_state(),
+            token: CancelToken::new(),
+        };
         let prom = op.make_promise();
         (op, prom)
     }
@@ -110,6 +224,7 @@ impl<T> Future<'static, T, NoopFulfiller<T>> {
 
 pub struct AsyncFuture<T> {
     state: StateRef<T>,
+    token: CancelToken,
 }
 impl<T> AsyncFuture<T> {
     pub fn ready(&self) -> bool {
@@ -118,9 +233,17 @@ impl<T> AsyncFuture<T> {
         }
     }
 
+    /// Drops the continuation, if any, and cancels the token shared with the
+    /// promise, so its fulfiller can stop work on it. The promise is ignored
+    /// from then on.
+    pub fn cancel(self) {
+        self.token.cancel();
+        self.state.take();
+    }
+
     pub fn map<U: 'static>(self, through: proc(T):'static -> U)
             -> AsyncFuture<U> {
-        let promise = Promise::new();
+        let promise = Promise::with_token(self.token.clone());
         let cont = promise.clone().into_future();
         match self.state.take() {
             Some(Ready(x)) => {
@@ -138,17 +261,15 @@ impl<T> AsyncFuture<T> {
 
     pub fn then<U: 'static>(self, next: proc(T):'static -> AsyncFuture<U>)
             -> AsyncFuture<U> {
-        let promise = Promise::new();
+        let promise = Promise::with_token(self.token.clone());
         let cont = promise.clone().into_future();
         match self.state.take() {
             Some(Ready(from)) => {
-                next(from).map(proc(to) { promise.fulfill(to); });
+                chain(next(from), promise);
             },
             None => {
                 self.state.put(Chained(proc(from) {
-                        next(from).map(proc(to) {
-                            promise.fulfill(to)
-                        });
+                    chain(next(from), promise);
                 }));
             }
             _ => unreachable!()
@@ -157,6 +278,243 @@ impl<T> AsyncFuture<T> {
     }
 }
 
+impl<T: Send> AsyncFuture<T> {
+    /// Hands the value over to a `SendFuture`, so that it can be waited for
+    /// on another thread. The promise must still be fulfilled on this one.
+    pub fn into_send(self) -> SendFuture<T> {
+        let (future, promise) = SendFuture::new_with_promise();
+        self.map(proc(value) promise.fulfill(value));
+        future
+    }
+}
+
+/// Combinators for fallible futures, such as the ones returned by RPC calls.
+/// An error skips every step up to the next `or_else` or `recover`, keeping
+/// its original code and description.
+impl<T: 'static> AsyncFuture<Result<T, Error>> {
+    /// Chains another fallible future to run with the value, if any.
+    pub fn and_then<U: 'static>(
+            self, next: proc(T):'static -> AsyncFuture<Result<U, Error>>)
+            -> AsyncFuture<Result<U, Error>> {
+        self.then(proc(result) {
+            match result {
+                Ok(value) => next(value),
+                Err(err) => ready(Err(err)),
+            }
+        })
+    }
+
+    pub fn map_ok<U: 'static>(self, through: proc(T):'static -> U)
+            -> AsyncFuture<Result<U, Error>> {
+        self.map(proc(result) result.map(through))
+    }
+
+    /// Chains another fallible future to run with the error, if any.
+    pub fn or_else(
+            self, next: proc(Error):'static -> AsyncFuture<Result<T, Error>>)
+            -> AsyncFuture<Result<T, Error>> {
+        self.then(proc(result) {
+            match result {
+                Ok(value) => ready(Ok(value)),
+                Err(err) => next(err),
+            }
+        })
+    }
+
+    pub fn map_err(self, through: proc(Error):'static -> Error)
+            -> AsyncFuture<Result<T, Error>> {
+        self.map(proc(result) result.map_err(through))
+    }
+
+    /// Turns an error into a value, leaving a future which cannot fail.
+    pub fn recover(self, through: proc(Error):'static -> T) -> AsyncFuture<T> {
+        self.map(proc(result) {
+            match result {
+                Ok(value) => value,
+                Err(err) => through(err),
+            }
+        })
+    }
+}
+
+fn ready<T>(value: T) -> AsyncFuture<T> {
+    AsyncFuture {
+        state: Rc::new(MoveCell::from_value(Ready(value))),
+        token: CancelToken::new(),
+    }
+}
+
+/// Fulfills `promise` with the value of `future`, cancelling `future` along
+/// with the promise.
+fn chain<T: 'static>(future: AsyncFuture<T>, promise: Promise<T>) {
+    promise.token.forward(vec![future.token.clone()]);
+    future.map(proc(value) { promise.fulfill(value); });
+}
+
+/// Waits for both futures, in whichever order they complete.
+pub fn join<A: 'static, B: 'static>(a: AsyncFuture<A>, b: AsyncFuture<B>)
+        -> AsyncFuture<(A, B)> {
+    let promise = Promise::new();
+    promise.token.forward(vec![a.token.clone(), b.token.clone()]);
+    let cont = promise.clone().into_future();
+    let join_a = Rc::new(RefCell::new(Join {
+        a: None,
+        b: None,
+        promise: Some(promise),
+    }));
+    let join_b = join_a.clone();
+    a.map(proc(a) {
+        join_a.borrow_mut().a = Some(a);
+        Join::complete(&join_a);
+    });
+    b.map(proc(b) {
+        join_b.borrow_mut().b = Some(b);
+        Join::complete(&join_b);
+    });
+    cont
+}
+
+/// Waits for all futures, keeping their values in the original order.
+pub fn join_all<T: 'static>(futures: Vec<AsyncFuture<T>>)
+        -> AsyncFuture<Vec<T>> {
+    if futures.is_empty() { return ready(Vec::new()); }
+    let promise = Promise::new();
+    promise.token.forward(tokens(&futures));
+    let cont = promise.clone().into_future();
+    let join = Rc::new(RefCell::new(JoinAll {
+        values: Vec::from_fn(futures.len(), |_| None),
+        remaining: futures.len(),
+        promise: Some(promise),
+    }));
+    for (index, future) in futures.into_iter().enumerate() {
+        let join = join.clone();
+        future.map(proc(value) {
+            let done = {
+                let mut join = join.borrow_mut();
+                join.values[mut][index] = Some(value);
+                join.remaining -= 1;
+                if join.remaining == 0 {
+                    let values = mem::replace(&mut join.values, Vec::new());
+                    Some((join.promise.take().unwrap(), values))
+                } else {
+                    None
+                }
+            };
+            match done {
+                Some((promise, values)) => {
+                    promise.fulfill(values.into_iter()
+                                          .map(|value| value.unwrap())
+                                          .collect());
+                },
+                None => {},
+            }
+        });
+    }
+    cont
+}
+
+/// Completes with the value of whichever future completes first; the other
+/// value is dropped.
+pub fn select<T: 'static>(a: AsyncFuture<T>, b: AsyncFuture<T>)
+        -> AsyncFuture<T> {
+    let promise = Promise::new();
+    promise.token.forward(vec![a.token.clone(), b.token.clone()]);
+    let cont = promise.clone().into_future();
+    let first_a = Rc::new(RefCell::new(Some(promise)));
+    let first_b = first_a.clone();
+    a.map(proc(value) {
+        let promise = first_a.borrow_mut().take();
+        match promise {
+            Some(promise) => promise.fulfill(value),
+            None => {},
+        }
+    });
+    b.map(proc(value) {
+        let promise = first_b.borrow_mut().take();
+        match promise {
+            Some(promise) => promise.fulfill(value),
+            None => {},
+        }
+    });
+    cont
+}
+
+/// Completes with the first success among `futures`, or with the last error
+/// if all of them fail.
+pub fn select_ok<T: 'static>(futures: Vec<AsyncFuture<Result<T, Error>>>)
+        -> AsyncFuture<Result<T, Error>> {
+    if futures.is_empty() {
+        return ready(Err(Error::with_desc(InternalServerError,
+                                          "select_ok of no futures")));
+    }
+    let promise = Promise::new();
+    promise.token.forward(tokens(&futures));
+    let cont = promise.clone().into_future();
+    let select = Rc::new(RefCell::new(SelectOk {
+        remaining: futures.len(),
+        promise: Some(promise),
+    }));
+    for future in futures.into_iter() {
+        let select = select.clone();
+        future.map(proc(result) {
+            let promise = {
+                let mut select = select.borrow_mut();
+                select.remaining -= 1;
+                if result.is_ok() || select.remaining == 0 {
+                    select.promise.take()
+                } else {
+                    None
+                }
+            };
+            match promise {
+                Some(promise) => promise.fulfill(result),
+                None => {},
+            }
+        });
+    }
+    cont
+}
+
+fn tokens<T>(futures: &Vec<AsyncFuture<T>>) -> Vec<CancelToken> {
+    futures.iter().map(|future| future.token.clone()).collect()
+}
+
+struct Join<A, B> {
+    a: Option<A>,
+    b: Option<B>,
+    promise: Option<Promise<(A, B)>>,
+}
+
+impl<A, B> Join<A, B> {
+    fn complete(join: &Rc<RefCell<Join<A, B>>>) {
+        let done = {
+            let mut join = join.borrow_mut();
+            if join.a.is_some() && join.b.is_some() {
+                Some((join.promise.take().unwrap(),
+                      (join.a.take().unwrap(), join.b.take().unwrap())))
+            } else {
+                None
+            }
+        };
+        // Fulfilled outside the borrow, since continuations run right away.
+        match done {
+            Some((promise, values)) => promise.fulfill(values),
+            None => {},
+        }
+    }
+}
+
+struct JoinAll<T> {
+    values: Vec<Option<T>>,
+    remaining: uint,
+    promise: Option<Promise<Vec<T>>>,
+}
+
+struct SelectOk<T> {
+    remaining: uint,
+    promise: Option<Promise<Result<T, Error>>>,
+}
+
 enum State<T> {
     Ready(T),
     Chained(proc(T):'static -> ()),
@@ -169,14 +527,19 @@ fn new_state<T>() -> StateRef<T> { Rc::new(MoveCell::new()) }
 
 #[cfg(test)]
 pub mod test {
+    use super::AsyncFuture;
     use super::Fulfiller;
     use super::Future;
     use super::Promise;
+    use super::{join, join_all, ready, select, select_ok};
+
+    use error::{Error, DeadlineExceeded, InternalServerError, NetworkError};
 
     use std::cell::Cell;
     use std::cell::RefCell;
     use std::default::Default;
     use std::rc::Rc;
+    use std::time::Duration;
 
     struct ConstantFulfiller<T: Clone + Default> {
         constant: T,
@@ -320,4 +683,211 @@ pub mod test {
         client5_clone.borrow_mut().poll();
         assert_eq!(fut.sync(), 2*3*5*7*11*13);
     }
+
+    fn pending<T>() -> (AsyncFuture<Result<T, Error>>,
+                        Promise<Result<T, Error>>) {
+        let (fut, prom) = Future::new_with_promise();
+        (fut.async(), prom)
+    }
+
+    #[test]
+    fn test_and_then() {
+        let (first, first_prom) = pending();
+        let (second, second_prom) = pending();
+        let (fut, prom) = Future::new_with_promise();
+
+        first.and_then(proc(x: uint) second.map_ok(proc(y: uint) x + y))
+            .map(proc(result) prom.fulfill(result));
+        first_prom.fulfill(Ok(2u));
+        assert!(!fut.ready());
+        second_prom.fulfill(Ok(3u));
+        assert_eq!(fut.sync().ok(), Some(5u));
+    }
+
+    #[test]
+    fn test_and_then_short_circuits() {
+        let called_getter = Rc::new(Cell::new(false));
+        let called_setter = called_getter.clone();
+        let (first, first_prom) = pending();
+        let (fut, prom) = Future::new_with_promise();
+
+        first.and_then(proc(x: uint) {
+                called_setter.set(true);
+                ready(Ok(x))
+            })
+            .map_ok(proc(x) x + 1)
+            .map(proc(result) prom.fulfill(result));
+        first_prom.fulfill(Err(Error::with_desc(NetworkError, "unreachable")));
+
+        let err = fut.sync().err().unwrap();
+        assert_eq!(err.code(), NetworkError);
+        assert_eq!(err.desc(), "unreachable");
+        assert!(!called_getter.get());
+    }
+
+    #[test]
+    fn test_or_else_and_map_err() {
+        let (first, first_prom) = pending();
+        let (fut, prom) = Future::new_with_promise();
+
+        first.map_err(proc(err) {
+                assert_eq!(err.code(), NetworkError);
+                Error::with_desc(DeadlineExceeded, "retried")
+            })
+            .or_else(proc(err) {
+                assert_eq!(err.code(), DeadlineExceeded);
+                ready(Err(Error::with_desc(InternalServerError, "gave up")))
+            })
+            .map(proc(result: Result<uint, Error>) prom.fulfill(result));
+        first_prom.fulfill(Err(Error::new(NetworkError)));
+
+        let err = fut.sync().err().unwrap();
+        assert_eq!(err.code(), InternalServerError);
+        assert_eq!(err.desc(), "gave up");
+    }
+
+    #[test]
+    fn test_recover() {
+        let (ok, ok_prom) = pending();
+        let (failed, failed_prom) = pending();
+        let (ok_fut, ok_res) = Future::new_with_promise();
+        let (failed_fut, failed_res) = Future::new_with_promise();
+
+        ok.recover(proc(_) 0u).map(proc(x) ok_res.fulfill(x));
+        failed.recover(proc(_) 0u).map(proc(x) failed_res.fulfill(x));
+        ok_prom.fulfill(Ok(7u));
+        failed_prom.fulfill(Err(Error::new(NetworkError)));
+        assert_eq!(ok_fut.sync(), 7u);
+        assert_eq!(failed_fut.sync(), 0u);
+    }
+
+    #[test]
+    fn test_join() {
+        let (a, a_prom) = Future::new_with_promise();
+        let (b, b_prom) = Future::new_with_promise();
+        let (fut, prom) = Future::new_with_promise();
+
+        join(a.async(), b.async()).map(proc(pair) prom.fulfill(pair));
+        b_prom.fulfill("b");
+        assert!(!fut.ready());
+        a_prom.fulfill(1u);
+        assert_eq!(fut.sync(), (1u, "b"));
+    }
+
+    #[test]
+    fn test_join_all() {
+        let mut futures = Vec::new();
+        let mut promises = Vec::new();
+        for _ in range(0u, 3) {
+            let (fut, prom) = Future::new_with_promise();
+            futures.push(fut.async());
+            promises.push(prom);
+        }
+        let (fut, prom) = Future::new_with_promise();
+
+        join_all(futures).map(proc(values) prom.fulfill(values));
+        for (index, promise) in promises.into_iter().enumerate().rev() {
+            assert!(!fut.ready());
+            promise.fulfill(index * 10);
+        }
+        assert_eq!(fut.sync(), vec![0u, 10, 20]);
+
+        let (fut, prom) = Future::new_with_promise();
+        join_all(Vec::<AsyncFuture<uint>>::new())
+            .map(proc(values) prom.fulfill(values));
+        assert_eq!(fut.sync(), vec![]);
+    }
+
+    #[test]
+    fn test_select() {
+        let (a, a_prom) = Future::new_with_promise();
+        let (b, b_prom) = Future::new_with_promise();
+        let (fut, prom) = Future::new_with_promise();
+
+        select(a.async(), b.async()).map(proc(value) prom.fulfill(value));
+        b_prom.fulfill("b");
+        a_prom.fulfill("a");
+        assert_eq!(fut.sync(), "b");
+    }
+
+    #[test]
+    fn test_select_ok() {
+        let (first, first_prom) = pending();
+        let (second, second_prom) = pending();
+        let (third, third_prom) = pending();
+        let (fut, prom) = Future::new_with_promise();
+
+        select_ok(vec![first, second, third])
+            .map(proc(result) prom.fulfill(result));
+        first_prom.fulfill(Err(Error::new(NetworkError)));
+        assert!(!fut.ready());
+        third_prom.fulfill(Ok(3u));
+        second_prom.fulfill(Ok(2u));
+        assert_eq!(fut.sync().ok(), Some(3u));
+
+        let (first, first_prom) = pending::<uint>();
+        let (second, second_prom) = pending();
+        let (fut, prom) = Future::new_with_promise();
+
+        select_ok(vec![first, second]).map(proc(result) prom.fulfill(result));
+        first_prom.fulfill(Err(Error::new(NetworkError)));
+        second_prom.fulfill(Err(Error::new(DeadlineExceeded)));
+        assert_eq!(fut.sync().err().unwrap().code(), DeadlineExceeded);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let called_getter = Rc::new(Cell::new(false));
+        let called_setter = called_getter.clone();
+        let cancelled_getter = Rc::new(Cell::new(false));
+        let cancelled_setter = cancelled_getter.clone();
+        let (fut, prom) = Future::new_with_promise();
+        let token = prom.cancel_token();
+        token.on_cancel(proc() cancelled_setter.set(true));
+
+        let cont = fut.async().map(proc(x: uint) {
+            called_setter.set(true);
+            x
+        });
+        assert!(!token.is_cancelled());
+        cont.cancel();
+        assert!(token.is_cancelled());
+        assert!(cancelled_getter.get());
+
+        prom.fulfill(1);
+        assert!(!called_getter.get());
+    }
+
+    #[test]
+    fn test_cancel_propagates() {
+        let (first, first_prom) = Future::new_with_promise();
+        let (second, second_prom) = Future::new_with_promise();
+        let (third, third_prom) = Future::new_with_promise();
+        let first_token = first_prom.cancel_token();
+        let second: AsyncFuture<uint> = second.async();
+        let third: AsyncFuture<uint> = third.async();
+
+        let chained = first.async().then(proc(_: uint) second);
+        let joined = join(chained, third);
+        first_prom.fulfill(1);
+        assert!(!second_prom.cancel_token().is_cancelled());
+
+        joined.cancel();
+        assert!(first_token.is_cancelled());
+        assert!(second_prom.cancel_token().is_cancelled());
+        assert!(third_prom.cancel_token().is_cancelled());
+    }
+
+    #[test]
+    fn test_sync_timeout() {
+        assert_eq!(Future::new_ready(5u)
+                       .sync_timeout(Duration::milliseconds(1)).ok(),
+                   Some(5u));
+
+        let (fut, prom) = Future::new_with_promise();
+        let err = fut.sync_timeout(Duration::seconds(10)).err().unwrap();
+        assert_eq!(err.code(), InternalServerError);
+        assert!(prom.cancel_token().is_cancelled());
+        prom.fulfill(1u);
+    }
 }
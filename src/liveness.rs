@@ -0,0 +1,109 @@
+use deadline;
+use std::default::Default;
+use std::i64;
+use std::time::Duration;
+
+/// How often peers exchange heartbeats, and how many heartbeat intervals may
+/// pass without hearing from a peer before it is considered dead.
+#[deriving(Clone, Show)]
+pub struct Liveness {
+    interval: Duration,
+    missed: uint,
+}
+
+impl Liveness {
+    pub fn new(interval: Duration, missed: uint) -> Liveness {
+        assert!(interval > Duration::zero(),
+                "Heartbeat interval must be positive.");
+        assert!(missed > 0, "At least one heartbeat must be missed.");
+        Liveness { interval: interval, missed: missed }
+    }
+
+    pub fn interval(&self) -> Duration { self.interval }
+
+    /// How long a peer may stay silent before it is considered dead.
+    pub fn timeout(&self) -> Duration { self.interval * self.missed as i32 }
+}
+
+impl Default for Liveness {
+    fn default() -> Liveness {
+        Liveness::new(Duration::seconds(1), 3)
+    }
+}
+
+/// Tracks whether a single peer is alive. Any message from the peer counts as
+/// a heartbeat.
+pub struct Peer {
+    liveness: Liveness,
+    last_seen_ns: u64,
+    alive: bool,
+}
+
+impl Peer {
+    pub fn new(liveness: Liveness) -> Peer {
+        Peer::new_at(liveness, deadline::now_ns())
+    }
+
+    pub fn liveness(&self) -> &Liveness { &self.liveness }
+
+    pub fn alive(&self) -> bool { self.alive }
+
+    /// Records a message from the peer. Returns whether the peer was dead,
+    /// i.e. came back.
+    pub fn seen(&mut self) -> bool {
+        self.seen_at(deadline::now_ns())
+    }
+
+    /// Returns whether the peer just died, i.e. was alive but has been
+    /// silent for longer than the liveness timeout.
+    pub fn check(&mut self) -> bool {
+        self.check_at(deadline::now_ns())
+    }
+
+    fn new_at(liveness: Liveness, now_ns: u64) -> Peer {
+        Peer {
+            liveness: liveness,
+            last_seen_ns: now_ns,
+            alive: true,
+        }
+    }
+
+    fn seen_at(&mut self, now_ns: u64) -> bool {
+        self.last_seen_ns = now_ns;
+        let revived = !self.alive;
+        self.alive = true;
+        revived
+    }
+
+    fn check_at(&mut self, now_ns: u64) -> bool {
+        let timeout_ns = self.liveness.timeout().num_nanoseconds()
+            .unwrap_or(i64::MAX) as u64;
+        if self.alive && now_ns - self.last_seen_ns > timeout_ns {
+            self.alive = false;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Liveness, Peer};
+    use std::time::Duration;
+
+    #[test]
+    fn test_peer() {
+        let liveness = Liveness::new(Duration::nanoseconds(10), 3);
+        assert_eq!(liveness.timeout(), Duration::nanoseconds(30));
+        let mut peer = Peer::new_at(liveness, 100);
+        assert!(!peer.check_at(130));
+        assert!(!peer.seen_at(130));
+        assert!(!peer.check_at(160));
+        assert!(peer.check_at(161));
+        assert!(!peer.alive());
+        assert!(!peer.check_at(200));
+        assert!(peer.seen_at(200));
+        assert!(peer.alive());
+    }
+}
@@ -1,40 +1,938 @@
+use deadline::{mod, Deadline};
+use error::{Error, NetworkError};
+use std::cell::{Cell, RefCell};
+use std::i64;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+use wire::{mod, SendQueue};
 use zmq;
-use zmq::PollItem;
 
-pub type ReadHandler<'a> = || : 'a -> ();
+/// Handlers own their state (`box move |&mut:| ...`) or borrow it for `'a`,
+/// so that handlers can register further handlers while dispatching.
+pub type ReadHandler<'a> = Box<FnMut() + 'a>;
+pub type TimerHandler<'a> = Box<FnMut() + 'a>;
+
+/// Builds the item polled for a socket, given the events of interest, e.g.
+/// `box |&: events: i16| socket.as_poll_item(events)`. Items are rebuilt
+/// before every poll, so the reactor never holds on to a socket.
+pub type PollSource<'a, 'b> = Box<Fn(i16) -> zmq::PollItem<'b> + 'a>;
+
+/// Called with the events which occurred on an item (`zmq::POLLIN`,
+/// `zmq::POLLOUT` and/or `zmq::POLLERR`).
+pub type EventHandler<'a> = Box<FnMut(i16) + 'a>;
+
+/// Reports how much work is still in flight, see `Reactor::watch_in_flight`.
+pub type InFlight<'a> = Box<Fn() -> uint + 'a>;
+
+/// Identifies an item registered with a `Reactor`.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub struct Token(uint);
+
+/// Tells when a dynamic timer is due next, if at all; see
+/// `Reactor::schedule_dynamic`.
+pub type DueHandler<'a> = Box<Fn() -> Option<Deadline> + 'a>;
+
+/// Cancels a timer scheduled on a `Reactor`. Handles can be cloned and moved
+/// into other handlers, including the timer's own.
+#[deriving(Clone)]
+pub struct TimerHandle {
+    cancelled: Rc<Cell<bool>>,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) { self.cancelled.set(true); }
+
+    pub fn cancelled(&self) -> bool { self.cancelled.get() }
+}
+
+/// Registers and deregisters items and timers on a `Reactor` while it is
+/// dispatching. Changes are applied as soon as the running handler returns;
+/// an item removed this way is not dispatched again, even if it was ready in
+/// the same poll.
+#[deriving(Clone)]
+pub struct Handle<'a, 'b> {
+    shared: Rc<RefCell<Shared<'a, 'b>>>,
+}
+
+impl<'a, 'b> Handle<'a, 'b> {
+    pub fn push_item(&self, source: PollSource<'a, 'b>,
+                     handler: ReadHandler<'a>) -> Token {
+        let mut shared = self.shared.borrow_mut();
+        let token = shared.new_token();
+        shared.commands.push(Add(token, source, Reader(handler)));
+        token
+    }
+
+    pub fn push_events(&self, source: PollSource<'a, 'b>,
+                       handler: EventHandler<'a>) -> Token {
+        let mut shared = self.shared.borrow_mut();
+        let token = shared.new_token();
+        shared.commands.push(Add(token, source, Events(handler)));
+        token
+    }
+
+    pub fn remove(&self, token: Token) {
+        self.shared.borrow_mut().commands.push(Remove(token));
+    }
+
+    pub fn set_interest(&self, token: Token, events: i16) {
+        self.shared.borrow_mut().commands.push(SetInterest(token, events));
+    }
+
+    pub fn schedule(&self, delay: Duration, handler: TimerHandler<'a>)
+            -> TimerHandle {
+        let (timer, handle) = new_timer(delay, None, handler);
+        self.shared.borrow_mut().commands.push(AddTimer(timer));
+        handle
+    }
+
+    pub fn schedule_repeating(&self, period: Duration,
+                              handler: TimerHandler<'a>) -> TimerHandle {
+        let (timer, handle) = new_timer(period, Some(period_ns(period)),
+                                        handler);
+        self.shared.borrow_mut().commands.push(AddTimer(timer));
+        handle
+    }
+
+    /// Makes `Reactor::run` return once the running handler is done.
+    pub fn stop(&self) {
+        self.shared.borrow_mut().state = Stopped;
+    }
+
+    /// Starts draining, see `StopHandle::drain`.
+    pub fn drain(&self, grace: Duration) {
+        self.shared.borrow_mut().begin_drain(grace);
+    }
+}
+
+/// Stops a `Reactor` from any thread, by sending commands to the `Control`
+/// registered with it.
+pub struct StopHandle {
+    socket: zmq::Socket,
+}
+
+impl StopHandle {
+    /// Makes `Reactor::run` return as soon as it is done with the current
+    /// round of handlers.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        wire::send_frames(&mut self.socket, &[STOP.to_vec()])
+    }
+
+    /// Pauses every acceptor and makes `Reactor::run` return once no work is
+    /// in flight, or after `grace` at the latest.
+    pub fn drain(&mut self, grace: Duration) -> Result<(), Error> {
+        let grace_ms = grace.num_milliseconds();
+        let mut frame = Vec::with_capacity(8);
+        for shift in range(0u, 8).rev() {
+            frame.push((grace_ms >> (shift * 8)) as u8);
+        }
+        wire::send_frames(&mut self.socket, &[DRAIN.to_vec(), frame])
+    }
+}
+
+/// Receiving end of a `StopHandle`; see `Reactor::push_control`.
+pub struct Control {
+    socket: zmq::Socket,
+}
+
+/// Creates a connected `StopHandle` and `Control`, either of which can be sent
+/// to another thread.
+pub fn control(ctx: &mut zmq::Context) -> Result<(StopHandle, Control), Error> {
+    let address = format!("inproc://zuffy-reactor-control-{}",
+                          NEXT_CONTROL.fetch_add(1, SeqCst));
+    let mut receiver = try!(ctx.socket(zmq::PULL).map_err(Error::from_zmq));
+    try!(receiver.bind(address.as_slice()).map_err(Error::from_zmq));
+    let mut sender = try!(ctx.socket(zmq::PUSH).map_err(Error::from_zmq));
+    try!(sender.connect(address.as_slice()).map_err(Error::from_zmq));
+    Ok((StopHandle { socket: sender }, Control { socket: receiver }))
+}
+
+static NEXT_CONTROL: AtomicUint = INIT_ATOMIC_UINT;
+static STOP: &'static [u8] = b"stop";
+static DRAIN: &'static [u8] = b"drain";
+
+#[deriving(Clone, PartialEq, Show)]
+enum RunState {
+    Running,
+    // Holds the time by which to stop, in nanoseconds.
+    Draining(u64),
+    Stopped,
+}
+
+enum Command<'a, 'b> {
+    Add(Token, PollSource<'a, 'b>, Handler<'a>),
+    Remove(Token),
+    SetInterest(Token, i16),
+    AddTimer(Timer<'a>),
+}
+
+struct Shared<'a, 'b> {
+    next_token: uint,
+    commands: Vec<Command<'a, 'b>>,
+    state: RunState,
+}
+
+impl<'a, 'b> Shared<'a, 'b> {
+    fn new_token(&mut self) -> Token {
+        self.next_token += 1;
+        Token(self.next_token - 1)
+    }
+
+    fn begin_drain(&mut self, grace: Duration) {
+        if self.state == Running {
+            self.state = Draining(Deadline::after(grace).as_ns());
+        }
+    }
+
+    fn handle_control(&mut self, frames: wire::Frames) -> Result<(), Error> {
+        if frames.len() == 1 && frames[0].as_slice() == STOP {
+            self.state = Stopped;
+        } else if frames.len() == 2 && frames[0].as_slice() == DRAIN
+                && frames[1].len() == 8 {
+            let grace_ms = frames[1].iter().fold(0i64, |ms, &byte| {
+                (ms << 8) | byte as i64
+            });
+            self.begin_drain(Duration::milliseconds(grace_ms));
+        } else {
+            return Err(Error::with_desc(NetworkError,
+                                        "malformed reactor control message"));
+        }
+        Ok(())
+    }
+}
+
+struct Timer<'a> {
+    due_ns: u64,
+    seq: u64,
+    period_ns: Option<u64>,
+    cancelled: Rc<Cell<bool>>,
+    handler: TimerHandler<'a>,
+}
+
+struct DynamicTimer<'a> {
+    due: DueHandler<'a>,
+    cancelled: Rc<Cell<bool>>,
+    handler: TimerHandler<'a>,
+}
+
+enum Handler<'a> {
+    // Only called on `POLLIN` (or `POLLERR`, so that the error is read).
+    Reader(ReadHandler<'a>),
+    Events(EventHandler<'a>),
+}
+
+struct Entry<'a, 'b> {
+    token: Token,
+    source: PollSource<'a, 'b>,
+    acceptor: bool,
+    // Requested events, before pausing and send queues are taken into
+    // account; see `interest`.
+    events: i16,
+    send_queue: Option<SendQueue>,
+    // `None` once removed during dispatch, until the entry is compacted away.
+    handler: Option<Handler<'a>>,
+}
 
 pub struct Reactor<'a, 'b> {
-    readers: Vec<ReadHandler<'a>>,
-    poll_set: Vec<zmq::PollItem<'b>>,
+    entries: Vec<Entry<'a, 'b>>,
+    // Sorted by descending (due_ns, seq), so the next timer to fire is last.
+    timers: Vec<Timer<'a>>,
+    next_seq: u64,
+    dynamic_timers: Vec<DynamicTimer<'a>>,
+    in_flight: Vec<InFlight<'a>>,
+    acceptors_paused: bool,
+    shared: Rc<RefCell<Shared<'a, 'b>>>,
 }
 
 impl<'a, 'b> Reactor<'a, 'b> {
     pub fn new() -> Reactor<'a, 'b> {
         Reactor {
-            readers: Vec::new(),
-            poll_set: Vec::new()
+            entries: Vec::new(),
+            timers: Vec::new(),
+            next_seq: 0,
+            dynamic_timers: Vec::new(),
+            in_flight: Vec::new(),
+            acceptors_paused: false,
+            shared: Rc::new(RefCell::new(Shared {
+                next_token: 0,
+                commands: Vec::new(),
+                state: Running,
+            })),
         }
     }
 
+    /// Returns a handle which handlers can use to modify this reactor.
+    pub fn handle(&self) -> Handle<'a, 'b> {
+        Handle { shared: self.shared.clone() }
+    }
+
+    /// Registers the socket polled through `source`, calling `handler`
+    /// whenever it is readable.
     pub fn push_item(&mut self,
-                     poll_item: zmq::PollItem,
-                     handler: ReadHandler<'a>) {
-        self.readers.push(handler);
-        self.poll_set.push(poll_item);
+                     source: PollSource<'a, 'b>,
+                     handler: ReadHandler<'a>) -> Token {
+        let token = self.shared.borrow_mut().new_token();
+        self.add_entry(token, false, source, Reader(handler));
+        token
+    }
+
+    /// Like `push_item`, but calls `handler` with the events that occurred,
+    /// for items polled for more than `POLLIN`.
+    pub fn push_events(&mut self,
+                       source: PollSource<'a, 'b>,
+                       handler: EventHandler<'a>) -> Token {
+        let token = self.shared.borrow_mut().new_token();
+        self.add_entry(token, false, source, Events(handler));
+        token
+    }
+
+    /// Like `push_events`, for items which accept new work (e.g. a server's
+    /// ROUTER socket). Acceptors are paused when the reactor starts draining,
+    /// though their send queue is still flushed.
+    pub fn push_acceptor(&mut self,
+                         source: PollSource<'a, 'b>,
+                         handler: EventHandler<'a>) -> Token {
+        let token = self.shared.borrow_mut().new_token();
+        self.add_entry(token, true, source, Events(handler));
+        token
+    }
+
+    /// Registers the receiving end of a `StopHandle`.
+    pub fn push_control(&mut self, control: Control) -> Token {
+        let socket = Rc::new(RefCell::new(control.socket));
+        let source = socket.clone();
+        let shared = self.shared.clone();
+        self.push_item(box move |&: events: i16| {
+            source.borrow().as_poll_item(events)
+        }, box move |&mut:| {
+            let frames = wire::recv_frames(&mut *socket.borrow_mut(), 0);
+            let result = frames.and_then(|frames| {
+                shared.borrow_mut().handle_control(frames)
+            });
+            match result {
+                Ok(()) => {},
+                Err(err) => error!("reactor: control: {}", err),
+            }
+        })
+    }
+
+    /// Adds a source of in-flight work: when draining, the reactor stops as
+    /// soon as all of them report zero.
+    pub fn watch_in_flight(&mut self, in_flight: InFlight<'a>) {
+        self.in_flight.push(in_flight);
+    }
+
+    /// Deregisters an item, returning whether it was registered.
+    pub fn remove(&mut self, token: Token) -> bool {
+        match self.find(token) {
+            Some(index) => {
+                self.entries.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Changes the events polled for on an item; zero pauses it. Returns
+    /// whether the item was registered.
+    pub fn set_interest(&mut self, token: Token, events: i16) -> bool {
+        match self.find(token) {
+            Some(index) => {
+                self.entries[index].events = events;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Polls an item for `POLLOUT` whenever `queue` is not empty, even while
+    /// the item is paused, so that its handler can flush the queue. Use
+    /// `push_events` for the item, since readers ignore `POLLOUT`. Returns
+    /// whether the item was registered.
+    pub fn set_send_queue(&mut self, token: Token, queue: SendQueue) -> bool {
+        match self.find(token) {
+            Some(index) => {
+                self.entries[index].send_queue = Some(queue);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Calls `handler` once, after `delay`.
+    pub fn schedule(&mut self, delay: Duration, handler: TimerHandler<'a>)
+            -> TimerHandle {
+        let (timer, handle) = new_timer(delay, None, handler);
+        self.insert_timer(timer);
+        handle
+    }
+
+    /// Calls `handler` every `period`, starting one `period` from now. Missed
+    /// ticks are skipped rather than fired in a burst.
+    pub fn schedule_repeating(&mut self,
+                              period: Duration,
+                              handler: TimerHandler<'a>) -> TimerHandle {
+        let (timer, handle) = new_timer(period, Some(period_ns(period)),
+                                        handler);
+        self.insert_timer(timer);
+        handle
+    }
+
+    /// Calls `handler` whenever the deadline returned by `due` has passed.
+    /// `due` is asked again before every poll, so the timer follows a
+    /// deadline which moves, such as the earliest one among the calls
+    /// pending on a `Channel`. `handler` should move the deadline on, or it
+    /// is called again on every poll.
+    pub fn schedule_dynamic(&mut self,
+                            due: DueHandler<'a>,
+                            handler: TimerHandler<'a>) -> TimerHandle {
+        let cancelled = Rc::new(Cell::new(false));
+        self.dynamic_timers.push(DynamicTimer {
+            due: due,
+            cancelled: cancelled.clone(),
+            handler: handler,
+        });
+        TimerHandle { cancelled: cancelled }
+    }
+
+    /// Starts draining, see `StopHandle::drain`.
+    pub fn drain(&mut self, grace: Duration) {
+        self.shared.borrow_mut().begin_drain(grace);
+        self.update_state();
+    }
+
+    pub fn stop(&mut self) {
+        self.shared.borrow_mut().state = Stopped;
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.shared.borrow().state == Stopped
+    }
+
+    /// Dispatches events until the reactor is stopped, or polling fails.
+    pub fn run(&mut self) -> Result<(), Error> {
+        while !self.stopped() {
+            try!(self.poll());
+        }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
+    /// Dispatches events until `condition` holds or the reactor is stopped.
+    /// Returns whether `condition` held.
+    pub fn run_until(&mut self, condition: || -> bool) -> Result<bool, Error> {
         loop {
-            self.poll();
+            if condition() { return Ok(true); }
+            if self.stopped() { return Ok(false); }
+            try!(self.poll());
+        }
+    }
+
+    /// Waits for events on the registered items or for the next timer to
+    /// expire, whichever comes first, and runs the corresponding handlers.
+    /// Fails if the items can't be polled, e.g. once the context is
+    /// terminated.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        self.apply_commands();
+        self.update_state();
+        if self.stopped() { return Ok(()); }
+        self.compact();
+        let mut poll_set: Vec<zmq::PollItem> = self.entries.iter()
+            .map(|entry| entry.source.call((self.interest(entry),)))
+            .collect();
+        try!(zmq::poll(poll_set[mut], self.next_timeout_ms())
+             .map_err(Error::from_zmq));
+        for index in range(0, poll_set.len()) {
+            let mut revents = poll_set[index].get_revents();
+            if self.entries[index].acceptor && self.acceptors_paused {
+                // Draining started earlier in this round: replies may still
+                // be flushed, but no new requests are taken.
+                revents &= !zmq::POLLIN;
+            }
+            match self.entries[index].handler {
+                Some(Reader(ref mut handler))
+                        if revents & (zmq::POLLIN | zmq::POLLERR) != 0 => {
+                    handler.call_mut(());
+                },
+                Some(Events(ref mut handler)) if revents != 0 => {
+                    handler.call_mut((revents,));
+                },
+                _ => continue,
+            }
+            self.apply_commands();
+            self.update_state();
+        }
+        self.fire_timers();
+        self.apply_commands();
+        self.compact();
+        self.update_state();
+        Ok(())
+    }
+
+    fn find(&self, token: Token) -> Option<uint> {
+        self.entries.iter().position(|entry| {
+            entry.token == token && entry.handler.is_some()
+        })
+    }
+
+    fn add_entry(&mut self, token: Token, acceptor: bool,
+                 source: PollSource<'a, 'b>, handler: Handler<'a>) {
+        self.entries.push(Entry {
+            token: token,
+            source: source,
+            acceptor: acceptor,
+            events: zmq::POLLIN,
+            send_queue: None,
+            handler: Some(handler),
+        });
+    }
+
+    /// The events actually polled for on an entry.
+    fn interest(&self, entry: &Entry) -> i16 {
+        let paused = entry.handler.is_none()
+            || (entry.acceptor && self.acceptors_paused);
+        let mut events = if paused { 0 } else { entry.events };
+        match entry.send_queue {
+            Some(ref queue) if !queue.is_empty()
+                    && entry.handler.is_some() => {
+                events |= zmq::POLLOUT;
+            },
+            _ => {},
         }
+        events
     }
 
-    fn poll(&mut self) {
-        zmq::poll(self.poll_set[mut], -1).unwrap();
-        for (index, &item) in self.poll_set.iter().enumerate() {
-            if item.get_revents() | zmq::POLLIN != 0 {
-                (*&mut self.readers[index])();
+    /// Pauses acceptors once draining starts, and stops once draining is
+    /// done.
+    fn update_state(&mut self) {
+        let drain_until_ns = match self.shared.borrow().state {
+            Draining(until_ns) => until_ns,
+            Running | Stopped => return,
+        };
+        self.acceptors_paused = true;
+        let idle = self.in_flight.iter().all(|in_flight| {
+            in_flight.call(()) == 0
+        });
+        if idle || deadline::now_ns() >= drain_until_ns {
+            self.shared.borrow_mut().state = Stopped;
+        }
+    }
+
+    /// Applies changes requested through `Handle`-s. Removed items are only
+    /// marked, since this may run in the middle of dispatching.
+    fn apply_commands(&mut self) {
+        let commands = mem::replace(&mut self.shared.borrow_mut().commands,
+                                    Vec::new());
+        for command in commands.into_iter() {
+            match command {
+                Add(token, source, handler) => {
+                    self.add_entry(token, false, source, handler);
+                },
+                Remove(token) => match self.find(token) {
+                    Some(index) => self.entries[index].handler = None,
+                    None => {},
+                },
+                SetInterest(token, events) => {
+                    self.set_interest(token, events);
+                },
+                AddTimer(timer) => self.insert_timer(timer),
             }
         }
     }
+
+    fn compact(&mut self) {
+        let mut index = 0;
+        while index < self.entries.len() {
+            if self.entries[index].handler.is_none() {
+                self.entries.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    fn insert_timer(&mut self, mut timer: Timer<'a>) {
+        timer.seq = self.next_seq;
+        self.next_seq += 1;
+        let key = (timer.due_ns, timer.seq);
+        let index = self.timers.iter()
+            .position(|t| (t.due_ns, t.seq) < key)
+            .unwrap_or(self.timers.len());
+        self.timers.insert(index, timer);
+    }
+
+    fn next_timeout_ms(&self) -> i64 {
+        let mut next = self.timers.last().map(|timer| {
+            Deadline::at_ns(timer.due_ns)
+        });
+        for timer in self.dynamic_timers.iter() {
+            if !timer.cancelled.get() {
+                next = Deadline::earliest(next, timer.due.call(()));
+            }
+        }
+        match self.shared.borrow().state {
+            Draining(until_ns) => {
+                next = Deadline::earliest(next,
+                                          Some(Deadline::at_ns(until_ns)));
+            },
+            Running | Stopped => {},
+        }
+        match next {
+            Some(deadline) => deadline.timeout_ms(),
+            None => -1,
+        }
+    }
+
+    fn fire_timers(&mut self) {
+        let now_ns = deadline::now_ns();
+        loop {
+            match self.timers.last() {
+                Some(timer) if timer.due_ns <= now_ns => {},
+                _ => break,
+            }
+            let mut timer = self.timers.pop().unwrap();
+            if timer.cancelled.get() { continue; }
+            timer.handler.call_mut(());
+
+            match timer.period_ns {
+                Some(period_ns) if !timer.cancelled.get() => {
+                    timer.due_ns += period_ns;
+                    if timer.due_ns <= now_ns {
+                        timer.due_ns = now_ns + period_ns;
+                    }
+                    self.insert_timer(timer);
+                },
+                _ => {},
+            }
+        }
+
+        self.dynamic_timers.retain(|timer| !timer.cancelled.get());
+        for timer in self.dynamic_timers.iter_mut() {
+            if timer.due.call(()).map_or(false, |due| due.expired()) {
+                timer.handler.call_mut(());
+            }
+        }
+    }
+}
+
+fn new_timer<'a>(delay: Duration, period_ns: Option<u64>,
+                 handler: TimerHandler<'a>) -> (Timer<'a>, TimerHandle) {
+    let cancelled = Rc::new(Cell::new(false));
+    let timer = Timer {
+        due_ns: Deadline::after(delay).as_ns(),
+        seq: 0,
+        period_ns: period_ns,
+        cancelled: cancelled.clone(),
+        handler: handler,
+    };
+    (timer, TimerHandle { cancelled: cancelled })
+}
+
+fn period_ns(period: Duration) -> u64 {
+    assert!(period > Duration::zero(), "Timer period must be positive.");
+    period.num_nanoseconds().unwrap_or(i64::MAX) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{control, Reactor, Token};
+    use deadline::Deadline;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use wire::{mod, SendQueue};
+    use zmq;
+
+    #[test]
+    fn test_one_shot_order() {
+        let order = Cell::new(0u);
+        let mut reactor = Reactor::new();
+        reactor.schedule(Duration::milliseconds(2), box |&mut:| {
+            assert_eq!(order.get(), 1);
+            order.set(2);
+        });
+        reactor.schedule(Duration::milliseconds(1), box |&mut:| {
+            assert_eq!(order.get(), 0);
+            order.set(1);
+        });
+        while order.get() < 2 {
+            reactor.poll().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_repeating_and_cancel() {
+        let ticks = Cell::new(0u);
+        let done = Cell::new(false);
+        let mut reactor = Reactor::new();
+        let handle = reactor.schedule_repeating(
+            Duration::milliseconds(1),
+            box |&mut:| ticks.set(ticks.get() + 1));
+        let cancelled = reactor.schedule(
+            Duration::milliseconds(1),
+            box |&mut:| panic!("Cancelled timer fired."));
+        cancelled.cancel();
+        reactor.schedule(Duration::milliseconds(20),
+                         box |&mut:| done.set(true));
+
+        while ticks.get() < 3 {
+            reactor.poll().unwrap();
+        }
+        handle.cancel();
+        assert!(handle.cancelled());
+        let ticks_at_cancel = ticks.get();
+        while !done.get() {
+            reactor.poll().unwrap();
+        }
+        assert_eq!(ticks.get(), ticks_at_cancel);
+    }
+
+    #[test]
+    fn test_cancel_from_handler() {
+        let ticks = Cell::new(0u);
+        let mut reactor = Reactor::new();
+        let handle = RefCell::new(None);
+        *handle.borrow_mut() = Some(reactor.schedule_repeating(
+            Duration::milliseconds(1),
+            box |&mut:| {
+                ticks.set(ticks.get() + 1);
+                handle.borrow().as_ref().unwrap().cancel();
+            }));
+        reactor.schedule(Duration::milliseconds(10), box |&mut:| {});
+        reactor.poll().unwrap();
+        reactor.poll().unwrap();
+        assert_eq!(ticks.get(), 1);
+    }
+
+    fn pair(ctx: &mut zmq::Context, address: &str)
+            -> (zmq::Socket, zmq::Socket) {
+        let mut receiver = ctx.socket(zmq::PULL).unwrap();
+        receiver.bind(address).unwrap();
+        let mut sender = ctx.socket(zmq::PUSH).unwrap();
+        sender.connect(address).unwrap();
+        (receiver, sender)
+    }
+
+    #[test]
+    fn test_remove_and_interest() {
+        let mut ctx = zmq::Context::new();
+        let (receiver, mut sender) =
+            pair(&mut ctx, "inproc://zuffy-reactor-remove");
+        let receiver = RefCell::new(receiver);
+        let received = Cell::new(0u);
+        let mut reactor = Reactor::new();
+        let token = reactor.push_item(
+            box |&: events: i16| receiver.borrow().as_poll_item(events),
+            box |&mut:| {
+                receiver.borrow_mut().recv_bytes(0).unwrap();
+                received.set(received.get() + 1);
+            });
+
+        sender.send(b"a", 0).unwrap();
+        reactor.poll().unwrap();
+        assert_eq!(received.get(), 1);
+
+        assert!(reactor.set_interest(token, 0));
+        sender.send(b"b", 0).unwrap();
+        reactor.schedule(Duration::milliseconds(5), box |&mut:| {});
+        reactor.poll().unwrap();
+        assert_eq!(received.get(), 1);
+
+        assert!(reactor.set_interest(token, zmq::POLLIN));
+        reactor.poll().unwrap();
+        assert_eq!(received.get(), 2);
+
+        assert!(reactor.remove(token));
+        assert!(!reactor.remove(token));
+        assert!(!reactor.set_interest(token, zmq::POLLIN));
+    }
+
+    #[test]
+    fn test_handle_during_dispatch() {
+        let mut ctx = zmq::Context::new();
+        let (first, mut first_sender) =
+            pair(&mut ctx, "inproc://zuffy-reactor-handle-first");
+        let first = RefCell::new(first);
+        let (second, mut second_sender) =
+            pair(&mut ctx, "inproc://zuffy-reactor-handle-second");
+        let second_calls = Rc::new(Cell::new(0u));
+        let second_token: Rc<Cell<Option<Token>>> = Rc::new(Cell::new(None));
+        let mut reactor = Reactor::new();
+
+        // The first handler registers the second socket once, then removes
+        // it on its next call, before the second one gets a chance to run.
+        let first_token = {
+            let first_ref = &first;
+            let mut second = Some(second);
+            let handle = reactor.handle();
+            let calls = second_calls.clone();
+            let token = second_token.clone();
+            reactor.push_item(
+                box move |&: events: i16| {
+                    first_ref.borrow().as_poll_item(events)
+                },
+                box move |&mut:| {
+                    first_ref.borrow_mut().recv_bytes(0).unwrap();
+                    match token.get() {
+                        None => {
+                            let socket =
+                                Rc::new(RefCell::new(second.take().unwrap()));
+                            let source = socket.clone();
+                            let calls = calls.clone();
+                            token.set(Some(handle.push_item(
+                                box move |&: events: i16| {
+                                    source.borrow().as_poll_item(events)
+                                },
+                                box move |&mut:| {
+                                    socket.borrow_mut().recv_bytes(0).unwrap();
+                                    calls.set(calls.get() + 1);
+                                })));
+                        },
+                        Some(second_token) => handle.remove(second_token),
+                    }
+                })
+        };
+
+        first_sender.send(b"register", 0).unwrap();
+        reactor.poll().unwrap();
+        assert!(second_token.get().is_some());
+
+        second_sender.send(b"ping", 0).unwrap();
+        reactor.poll().unwrap();
+        assert_eq!(second_calls.get(), 1);
+
+        second_sender.send(b"ping", 0).unwrap();
+        first_sender.send(b"remove", 0).unwrap();
+        reactor.schedule(Duration::milliseconds(5), box |&mut:| {});
+        reactor.poll().unwrap();
+        assert_eq!(second_calls.get(), 1);
+
+        reactor.handle().remove(first_token);
+        reactor.schedule(Duration::milliseconds(1), box |&mut:| {});
+        reactor.poll().unwrap();
+        assert!(!reactor.remove(first_token));
+    }
+
+    #[test]
+    fn test_dynamic_timer() {
+        let due = Cell::new(None);
+        let fired = Cell::new(0u);
+        let mut reactor = Reactor::new();
+        reactor.schedule_dynamic(box |&:| due.get(), box |&mut:| {
+            fired.set(fired.get() + 1);
+            due.set(None);
+        });
+        due.set(Some(Deadline::after(Duration::milliseconds(1))));
+        while fired.get() < 1 {
+            reactor.poll().unwrap();
+        }
+
+        // Moving the deadline re-arms the timer.
+        due.set(Some(Deadline::after(Duration::milliseconds(1))));
+        while fired.get() < 2 {
+            reactor.poll().unwrap();
+        }
+        assert!(due.get().is_none());
+    }
+
+    #[test]
+    fn test_send_queue() {
+        let mut ctx = zmq::Context::new();
+        let mut sender = ctx.socket(zmq::PUSH).unwrap();
+        sender.bind("inproc://zuffy-reactor-send-queue").unwrap();
+        let queue = SendQueue::new();
+        queue.push(vec![b"a".to_vec()]);
+        queue.push(vec![b"b".to_vec(), b"c".to_vec()]);
+
+        // Without a peer the socket is not writable, so nothing is sent.
+        queue.flush(&mut sender).unwrap();
+        assert_eq!(queue.len(), 2);
+
+        let sender = RefCell::new(sender);
+        let mut reactor = Reactor::new();
+        let token = reactor.push_events(
+            box |&: events: i16| sender.borrow().as_poll_item(events),
+            box |&mut: revents: i16| {
+                assert_eq!(revents, zmq::POLLOUT);
+                queue.flush(&mut *sender.borrow_mut()).unwrap();
+            });
+        assert!(reactor.set_interest(token, 0));
+        assert!(reactor.set_send_queue(token, queue.clone()));
+        reactor.schedule(Duration::milliseconds(5), box |&mut:| {});
+        reactor.poll().unwrap();
+        assert_eq!(queue.len(), 2);
+
+        let mut receiver = ctx.socket(zmq::PULL).unwrap();
+        receiver.connect("inproc://zuffy-reactor-send-queue").unwrap();
+        assert!(reactor.run_until(|| queue.is_empty()).unwrap());
+        assert_eq!(wire::recv_frames(&mut receiver, 0).unwrap(),
+                   vec![b"a".to_vec()]);
+        assert_eq!(wire::recv_frames(&mut receiver, 0).unwrap(),
+                   vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let fired = Cell::new(false);
+        let mut reactor = Reactor::new();
+        reactor.schedule(Duration::milliseconds(1),
+                         box |&mut:| fired.set(true));
+        assert!(reactor.run_until(|| fired.get()).unwrap());
+
+        reactor.handle().stop();
+        assert!(!reactor.run_until(|| false).unwrap());
+    }
+
+    #[test]
+    fn test_stop_handle() {
+        let mut ctx = zmq::Context::new();
+        let (mut stop, control) = control(&mut ctx).unwrap();
+        let mut reactor = Reactor::new();
+        reactor.push_control(control);
+        spawn(proc() {
+            stop.stop().unwrap();
+        });
+        reactor.run().unwrap();
+        assert!(reactor.stopped());
+    }
+
+    #[test]
+    fn test_drain_waits_for_in_flight() {
+        let mut ctx = zmq::Context::new();
+        let (receiver, mut sender) =
+            pair(&mut ctx, "inproc://zuffy-reactor-drain");
+        let receiver = RefCell::new(receiver);
+        let (mut stop, control) = control(&mut ctx).unwrap();
+        let accepted = Cell::new(0u);
+        let in_flight = Cell::new(1u);
+        let mut reactor = Reactor::new();
+        reactor.push_control(control);
+        reactor.push_acceptor(
+            box |&: events: i16| receiver.borrow().as_poll_item(events),
+            box |&mut: _revents: i16| {
+                receiver.borrow_mut().recv_bytes(0).unwrap();
+                accepted.set(accepted.get() + 1);
+            });
+        reactor.watch_in_flight(box |&:| in_flight.get());
+        reactor.schedule(Duration::milliseconds(10),
+                         box |&mut:| in_flight.set(0));
+
+        stop.drain(Duration::seconds(10)).unwrap();
+        sender.send(b"late", 0).unwrap();
+        reactor.run().unwrap();
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(accepted.get(), 0);
+    }
+
+    #[test]
+    fn test_drain_grace_period() {
+        let in_flight = Cell::new(1u);
+        let mut reactor = Reactor::new();
+        reactor.watch_in_flight(box |&:| in_flight.get());
+        reactor.drain(Duration::milliseconds(5));
+        assert!(!reactor.stopped());
+        reactor.run().unwrap();
+        assert_eq!(in_flight.get(), 1);
+    }
 }
@@ -0,0 +1,109 @@
+use error::{ErrorCode, DeadlineExceeded, NetworkError, Unavailable};
+use std::cmp;
+use std::default::Default;
+use std::i64;
+use std::rand;
+use std::time::Duration;
+
+/// How calls to idempotent methods are retried after failing, see
+/// `Channel::set_retry_policy`.
+///
+/// Before each retry the client backs off for a random time of up to
+/// `initial_backoff`, doubled for every attempt made so far and capped at
+/// `max_backoff`. The randomness keeps clients which failed together from
+/// retrying together. A call is never retried past its deadline.
+#[deriving(Show)]
+pub struct RetryPolicy {
+    max_attempts: uint,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    attempt_timeout: Option<Duration>,
+    retryable: Vec<ErrorCode>,
+}
+
+impl RetryPolicy {
+    /// Makes up to `max_attempts` attempts, the first one included, retrying
+    /// on `NetworkError`, `DeadlineExceeded` and `Unavailable`.
+    pub fn new(max_attempts: uint, initial_backoff: Duration,
+               max_backoff: Duration) -> RetryPolicy {
+        assert!(max_attempts > 0, "At least one attempt must be made.");
+        RetryPolicy {
+            max_attempts: max_attempts,
+            initial_backoff: initial_backoff,
+            max_backoff: max_backoff,
+            attempt_timeout: None,
+            retryable: vec![NetworkError, DeadlineExceeded, Unavailable],
+        }
+    }
+
+    /// Gives every attempt at most `timeout`, within the deadline of the
+    /// call, so an attempt which gets no reply is retried. Without it, only
+    /// attempts failing before the deadline of the call are retried.
+    pub fn set_attempt_timeout(&mut self, timeout: Option<Duration>) {
+        self.attempt_timeout = timeout;
+    }
+
+    /// Sets the error codes on which calls are retried.
+    pub fn set_retryable(&mut self, codes: Vec<ErrorCode>) {
+        self.retryable = codes;
+    }
+
+    pub fn max_attempts(&self) -> uint { self.max_attempts }
+
+    pub fn attempt_timeout(&self) -> Option<Duration> { self.attempt_timeout }
+
+    pub fn retryable(&self, code: ErrorCode) -> bool {
+        self.retryable.contains(&code)
+    }
+
+    /// A random time to wait after `attempts` failed attempts.
+    pub fn backoff(&self, attempts: uint) -> Duration {
+        let ceiling_ns =
+            self.ceiling(attempts).num_nanoseconds().unwrap_or(i64::MAX);
+        Duration::nanoseconds(
+            (ceiling_ns as f64 * rand::random::<f64>()) as i64)
+    }
+
+    fn ceiling(&self, attempts: uint) -> Duration {
+        let mut ceiling = self.initial_backoff;
+        for _ in range(1, attempts) {
+            if ceiling >= self.max_backoff { break; }
+            ceiling = ceiling * 2;
+        }
+        cmp::min(ceiling, self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(3, Duration::milliseconds(10), Duration::seconds(1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RetryPolicy;
+    use error::{InternalServerError, NetworkError, Unavailable};
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let mut policy = RetryPolicy::new(
+            5, Duration::milliseconds(10), Duration::milliseconds(30));
+        assert_eq!(policy.ceiling(1), Duration::milliseconds(10));
+        assert_eq!(policy.ceiling(2), Duration::milliseconds(20));
+        assert_eq!(policy.ceiling(3), Duration::milliseconds(30));
+        assert_eq!(policy.ceiling(100), Duration::milliseconds(30));
+        for attempts in range(1u, 10) {
+            let backoff = policy.backoff(attempts);
+            assert!(backoff >= Duration::zero());
+            assert!(backoff <= policy.ceiling(attempts));
+        }
+
+        assert!(policy.retryable(NetworkError));
+        assert!(policy.retryable(Unavailable));
+        assert!(!policy.retryable(InternalServerError));
+        policy.set_retryable(vec![InternalServerError]);
+        assert!(policy.retryable(InternalServerError));
+    }
+}
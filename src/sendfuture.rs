@@ -0,0 +1,282 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, SeqCst};
+
+/// Fulfills a `SendFuture`; unlike `future::Promise` it can be sent to and
+/// fulfilled on another thread, e.g. a reactor thread answering calls made by
+/// workers.
+///
+/// Dropping the promise without fulfilling it breaks the future, see
+/// `SendFuture::sync`.
+pub struct SendPromise<T> {
+    state: StateRef<T>,
+    cancelled: CancelRef,
+    fulfilled: bool,
+}
+
+impl<T: Send> SendPromise<T> {
+    /// Resolves the future, running its continuation, if any, on the calling
+    /// thread. Does nothing if the future was cancelled.
+    pub fn fulfill(mut self, value: T) {
+        self.fulfilled = true;
+        if self.cancelled() { return; }
+        let next = {
+            let mut state = self.state.lock();
+            match mem::replace(&mut *state, Pending) {
+                Pending => {
+                    *state = Ready(value);
+                    state.cond.broadcast();
+                    return;
+                },
+                Chained(next) => next,
+                Cancelled => {
+                    *state = Cancelled;
+                    return;
+                },
+                Ready(_) | Broken => unreachable!(),
+            }
+        };
+        // Continuations run without holding the lock, since they may block.
+        next(value);
+    }
+
+    /// Whether the future, or one derived from it with `map` or `then`, was
+    /// cancelled.
+    pub fn cancelled(&self) -> bool { self.cancelled.is_set() }
+}
+
+#[unsafe_destructor]
+impl<T: Send> Drop for SendPromise<T> {
+    fn drop(&mut self) {
+        if self.fulfilled { return; }
+        let mut state = self.state.lock();
+        // Dropping a continuation drops its promise too, breaking the
+        // futures derived from this one.
+        match mem::replace(&mut *state, Broken) {
+            Pending | Chained(_) => state.cond.broadcast(),
+            other => *state = other,
+        }
+    }
+}
+
+/// A `Send` counterpart of `future::AsyncFuture`: the value may be produced
+/// on one thread and consumed on another.
+pub struct SendFuture<T> {
+    state: StateRef<T>,
+    cancelled: CancelRef,
+}
+
+impl<T: Send> SendFuture<T> {
+    pub fn new_with_promise() -> (SendFuture<T>, SendPromise<T>) {
+        SendFuture::with_cancelled(CancelFlag::new())
+    }
+
+    pub fn new_ready(value: T) -> SendFuture<T> {
+        SendFuture {
+            state: Arc::new(Mutex::new(Ready(value))),
+            cancelled: CancelFlag::new(),
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        match *self.state.lock() {
+            Ready(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Blocks until the promise is fulfilled. Panics if it was dropped
+    /// without being fulfilled, like `future::Future::sync`.
+    pub fn sync(self) -> T {
+        let mut state = self.state.lock();
+        loop {
+            match mem::replace(&mut *state, Pending) {
+                Ready(value) => return value,
+                Pending => state.cond.wait(),
+                Broken => panic!("Promise was dropped unfulfilled."),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Drops the value or the continuation; the promise is ignored from then
+    /// on, and so are the ones of the futures `then` chained this one to.
+    pub fn cancel(self) {
+        self.cancelled.set();
+        *self.state.lock() = Cancelled;
+    }
+
+    /// Runs `through` on the thread which fulfills the promise, or right away
+    /// if it already was.
+    pub fn map<U: Send>(self, through: proc(T):Send -> U) -> SendFuture<U> {
+        let (cont, promise) =
+            SendFuture::with_cancelled(self.cancelled.clone());
+        self.chain(proc(value) promise.fulfill(through(value)));
+        cont
+    }
+
+    pub fn then<U: Send>(self, next: proc(T):Send -> SendFuture<U>)
+            -> SendFuture<U> {
+        let cancelled = self.cancelled.clone();
+        let (cont, promise) =
+            SendFuture::with_cancelled(self.cancelled.clone());
+        self.chain(proc(value) {
+            let next = next(value);
+            cancelled.forward(next.cancelled.clone());
+            next.chain(proc(value) promise.fulfill(value));
+        });
+        cont
+    }
+
+    fn with_cancelled(cancelled: CancelRef)
+            -> (SendFuture<T>, SendPromise<T>) {
+        let state = Arc::new(Mutex::new(Pending));
+        (SendFuture { state: state.clone(), cancelled: cancelled.clone() },
+         SendPromise { state: state, cancelled: cancelled, fulfilled: false })
+    }
+
+    fn chain(self, next: proc(T):Send -> ()) {
+        let value = {
+            let mut state = self.state.lock();
+            match mem::replace(&mut *state, Pending) {
+                Pending => {
+                    *state = Chained(next);
+                    return;
+                },
+                Ready(value) => value,
+                // The continuation never runs.
+                Broken => return,
+                _ => unreachable!(),
+            }
+        };
+        next(value);
+    }
+}
+
+enum State<T> {
+    Pending,
+    Ready(T),
+    Chained(proc(T):Send -> ()),
+    Cancelled,
+    // The promise was dropped without being fulfilled.
+    Broken,
+}
+
+type StateRef<T> = Arc<Mutex<State<T>>>;
+
+// Shared by a future, its promise and the futures derived from it with `map`
+// and `then`; a `Send` counterpart of `future::CancelToken`.
+struct CancelFlag {
+    cancelled: AtomicBool,
+    // Flags of the futures `then` chained to, which are cancelled along.
+    forwards: Mutex<Vec<CancelRef>>,
+}
+
+type CancelRef = Arc<CancelFlag>;
+
+impl CancelFlag {
+    fn new() -> CancelRef {
+        Arc::new(CancelFlag {
+            cancelled: AtomicBool::new(false),
+            forwards: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn is_set(&self) -> bool { self.cancelled.load(SeqCst) }
+
+    fn set(&self) {
+        if self.cancelled.swap(true, SeqCst) { return; }
+        let forwards = mem::replace(&mut *self.forwards.lock(), Vec::new());
+        for flag in forwards.iter() {
+            flag.set();
+        }
+    }
+
+    /// Cancels `flag` whenever this one is, or right away if it already is.
+    fn forward(&self, flag: CancelRef) {
+        {
+            let mut forwards = self.forwards.lock();
+            if !self.is_set() {
+                forwards.push(flag);
+                return;
+            }
+        }
+        flag.set();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SendFuture;
+    use future::Future;
+    use std::io::timer;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, SeqCst};
+    use std::task;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fulfill_from_other_thread() {
+        let (fut, prom) = SendFuture::new_with_promise();
+        spawn(proc() {
+            timer::sleep(Duration::milliseconds(5));
+            prom.fulfill(5u);
+        });
+        assert_eq!(fut.sync(), 5u);
+    }
+
+    #[test]
+    fn test_map_then() {
+        let (first, first_prom) = SendFuture::new_with_promise();
+        let (second, second_prom) = SendFuture::new_with_promise();
+        let fut = first
+            .then(proc(x: uint) second.map(proc(y: uint) x * y))
+            .map(proc(x) x + 1);
+        spawn(proc() first_prom.fulfill(6u));
+        spawn(proc() second_prom.fulfill(7u));
+        assert_eq!(fut.sync(), 43u);
+
+        let fut = SendFuture::new_ready(2u).map(proc(x) x * 3);
+        assert!(fut.ready());
+        assert_eq!(fut.sync(), 6u);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let ran_getter = Arc::new(AtomicBool::new(false));
+        let ran_setter = ran_getter.clone();
+        let (fut, prom) = SendFuture::new_with_promise();
+        fut.map(proc(_: uint) ran_setter.store(true, SeqCst)).cancel();
+        assert!(prom.cancelled());
+        prom.fulfill(1u);
+        assert!(!ran_getter.load(SeqCst));
+    }
+
+    #[test]
+    fn test_dropped_promise() {
+        let (fut, prom) = SendFuture::<uint>::new_with_promise();
+        let fut = fut.map(proc(x) x + 1);
+        spawn(proc() drop(prom));
+        assert!(task::try(proc() fut.sync()).is_err());
+    }
+
+    #[test]
+    fn test_cancel_then() {
+        let (first, first_prom) = SendFuture::new_with_promise();
+        let (second, second_prom) = SendFuture::<uint>::new_with_promise();
+        let fut = first.then(proc(_: uint) second);
+        first_prom.fulfill(1u);
+        fut.cancel();
+        assert!(second_prom.cancelled());
+    }
+
+    #[test]
+    fn test_into_send() {
+        let (fut, prom) = Future::new_with_promise();
+        let fut = fut.async().into_send();
+        let (result, result_prom) = SendFuture::new_with_promise();
+        spawn(proc() result_prom.fulfill(fut.sync() * 2));
+        prom.fulfill(4u);
+        assert_eq!(result.sync(), 8u);
+    }
+}
@@ -0,0 +1,665 @@
+use codec::{mod, Codec};
+use deadline::{mod, Deadline};
+use error::{Error, Cancelled, DeadlineExceeded, InvalidArgument, NetworkError};
+use error::Unimplemented;
+use executor::{Executor, Inline, Job};
+use flow::{CreditWindow, Grants, INITIAL_CREDITS};
+use liveness::{Liveness, Peer};
+use std::collections::{HashMap, RingBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, SeqCst};
+use std::time::Duration;
+use wire::{mod, Cancel, Credit, Envelope, Frames, Heartbeat, Kind, Metadata,
+           Request, SendQueue, StreamEnd, StreamItem, StreamRequest};
+use zmq;
+
+/// Routes a decoded method name and raw payload to a service implementation.
+///
+/// Implemented by the `Dispatcher` generated by `zuffy_service!`.
+pub trait Dispatch {
+    fn dispatch(&mut self, ctx: &Context, method: &str, payload: &[u8])
+        -> Result<Vec<u8>, Error>;
+
+    /// Whether `method` streams its responses, in which case calls to it go
+    /// to `dispatch_stream`, or to `dispatch_bidi` if it takes a stream of
+    /// requests too.
+    fn streaming(&self, _method: &str) -> bool { false }
+
+    /// Whether `method` takes a stream of requests, in which case calls to
+    /// it go to `dispatch_client_stream`, or to `dispatch_bidi` if it
+    /// streams its responses too.
+    fn takes_stream(&self, _method: &str) -> bool { false }
+
+    /// Dispatches a call to a streaming method, passing every encoded
+    /// response to `emit`. Returning ends the stream.
+    fn dispatch_stream(&mut self, _ctx: &Context, method: &str,
+                       _payload: &[u8], _emit: |Vec<u8>|)
+            -> Result<(), Error> {
+        Err(unknown_method(method))
+    }
+
+    /// Dispatches a call to a method taking a stream of requests.
+    fn dispatch_client_stream(&mut self, _ctx: &Context, method: &str,
+                              _requests: &mut Requests)
+            -> Result<Vec<u8>, Error> {
+        Err(unknown_method(method))
+    }
+
+    /// Dispatches a call to a method taking a stream of requests and
+    /// streaming its responses, see `dispatch_stream`.
+    fn dispatch_bidi(&mut self, _ctx: &Context, method: &str,
+                     _requests: &mut Requests, _emit: |Vec<u8>|)
+            -> Result<(), Error> {
+        Err(unknown_method(method))
+    }
+}
+
+/// Per-request state handed to service implementations.
+pub struct Context {
+    deadline: Option<Deadline>,
+    metadata: Metadata,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Context {
+    pub fn new(deadline: Option<Deadline>, metadata: Metadata) -> Context {
+        Context {
+            deadline: deadline,
+            metadata: metadata,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the caller cancelled the request. Long running handlers
+    /// should check this and give up early.
+    pub fn cancelled(&self) -> bool { self.cancelled.load(SeqCst) }
+
+    /// Fails with `Cancelled` if the caller cancelled the request, for use
+    /// with `try!`.
+    pub fn check_cancelled(&self) -> Result<(), Error> {
+        if self.cancelled() {
+            Err(Error::with_desc(Cancelled, "request cancelled by caller"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// When the caller stops waiting for the reply. Pass the context to
+    /// `Channel::within` to give downstream calls the same deadline.
+    pub fn deadline(&self) -> Option<Deadline> { self.deadline }
+
+    pub fn metadata(&self) -> &Metadata { &self.metadata }
+}
+
+/// Where the implementation of a streaming method sends its responses.
+pub struct Sink<'a, T> {
+    ctx: &'a Context,
+    encode: |&T|:'a -> Result<Vec<u8>, Error>,
+    emit: |Vec<u8>|:'a,
+}
+
+impl<'a, T> Sink<'a, T> {
+    /// Sends a single response. Fails once the caller cancelled the request
+    /// or its deadline passed, at which point the handler should give up.
+    /// Waits while the caller grants no credits, see `Endpoint`.
+    pub fn send(&mut self, response: &T) -> Result<(), Error> {
+        try!(self.ctx.check_cancelled());
+        if self.ctx.deadline().map_or(false, |d| d.expired()) {
+            return Err(Error::with_desc(DeadlineExceeded,
+                                        "deadline exceeded mid-stream"));
+        }
+        let payload = try!((self.encode)(response));
+        (self.emit)(payload);
+        Ok(())
+    }
+}
+
+/// The requests streamed to a call, as the endpoint receives them. Handed to
+/// the call's `Job`, which reads them through `Requests`.
+pub struct Incoming {
+    receiver: Receiver<Streamed>,
+}
+
+enum Streamed {
+    Item(Vec<u8>),
+    HalfClosed,
+}
+
+impl Incoming {
+    fn new() -> (Sender<Streamed>, Incoming) {
+        let (sender, receiver) = channel();
+        (sender, Incoming { receiver: receiver })
+    }
+}
+
+/// The raw requests of a call taking a stream of them, see `Source`.
+pub struct Requests<'a> {
+    incoming: Incoming,
+    deadline: Option<Deadline>,
+    idle: Option<Duration>,
+    grants: Grants,
+    grant: |u32|:'a,
+    done: bool,
+}
+
+impl<'a> Requests<'a> {
+    /// Reads `incoming` until `deadline`, that of the call, waiting no longer
+    /// than `idle` for each request, and passes the credits due to the
+    /// client to `grant` as requests are taken.
+    pub fn new(incoming: Incoming, deadline: Option<Deadline>,
+               idle: Option<Duration>, grant: |u32|:'a) -> Requests<'a> {
+        Requests {
+            incoming: incoming,
+            deadline: deadline,
+            idle: idle,
+            grants: Grants::new(INITIAL_CREDITS),
+            grant: grant,
+            done: false,
+        }
+    }
+
+    /// Blocks until the next request arrives, returning `None` once the
+    /// client half-closed the stream. Fails, once, with `Cancelled` if the
+    /// client cancelled the call instead, or with `DeadlineExceeded` if the
+    /// deadline of the call, or the idle timeout, passed first.
+    pub fn recv(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.done { return None; }
+        let until = Deadline::earliest(self.deadline,
+                                       self.idle.map(Deadline::after));
+        let received = deadline::recv_until(&self.incoming.receiver, until);
+        self.done = true;
+        match received {
+            Some(Ok(Item(payload))) => {
+                self.done = false;
+                match self.grants.consumed() {
+                    Some(credits) => (self.grant)(credits),
+                    None => {},
+                }
+                Some(Ok(payload))
+            },
+            Some(Ok(HalfClosed)) => None,
+            Some(Err(())) => {
+                Some(Err(Error::with_desc(Cancelled,
+                                          "request stream aborted")))
+            },
+            None => {
+                Some(Err(Error::with_desc(DeadlineExceeded,
+                                          "no request streamed in time")))
+            },
+        }
+    }
+}
+
+/// Where the implementation of a method taking a stream of requests receives
+/// them, by iterating until the client half-closes the stream. Requests
+/// which can't be decoded come out as `InvalidArgument` errors.
+pub struct Source<'a, T> {
+    next: ||:'a -> Option<Result<Vec<u8>, Error>>,
+    decode: |&[u8]|:'a -> Result<T, Error>,
+}
+
+impl<'a, T> Iterator<Result<T, Error>> for Source<'a, T> {
+    fn next(&mut self) -> Option<Result<T, Error>> {
+        (self.next)().map(|request| {
+            request.and_then(|payload| (self.decode)(payload.as_slice()))
+        })
+    }
+}
+
+// Flow control of the responses streamed by a call.
+struct ResponseWindow {
+    window: CreditWindow,
+    // Responses held back for lack of credits, and possibly the last message
+    // after them, with their kind.
+    held: RingBuf<(Kind, Frames)>,
+    // Passes credits on to the producer, which waits for them on one of the
+    // executor's threads.
+    producer: Sender<u32>,
+}
+
+impl ResponseWindow {
+    fn new(producer: Sender<u32>) -> ResponseWindow {
+        ResponseWindow {
+            window: CreditWindow::new(INITIAL_CREDITS),
+            held: RingBuf::new(),
+            producer: producer,
+        }
+    }
+}
+
+/// How long a handler waits by default for the next request streamed to its
+/// call, or for credits to stream its responses with, see
+/// `Endpoint::set_stream_timeout`.
+pub const DEFAULT_STREAM_TIMEOUT_SECS: i64 = 60;
+
+/// Server side of a ROUTER socket, or of a DEALER connected to the backend of
+/// a `broker::Broker`, running requests with an `Executor`.
+///
+/// Replies which the socket cannot take right away are queued; register the
+/// endpoint with `Reactor::push_events` and `Reactor::set_send_queue` and
+/// call `handle_events` to have them flushed once it is writable. A plain
+/// ROUTER drops messages to a peer over its high-water mark instead of
+/// refusing them, so ROUTER sockets are switched to `ROUTER_MANDATORY`.
+/// Executors which produce replies elsewhere also need their
+/// `executor_poll_item` registered, with a handler calling `process_reply`.
+///
+/// Calls taking a stream of requests are handed them as they arrive, and
+/// grant the client credits as they take them, see `flow`. Like other
+/// streaming calls, they need a `ThreadPool`.
+///
+/// Streamed responses take credits granted by the client. Responses the
+/// executor produces without credits are held back, and handlers wait for
+/// credits once they run out.
+pub struct Endpoint<E> {
+    socket: zmq::Socket,
+    executor: E,
+    send_queue: SendQueue,
+    // Cancellation flags of the requests being handled, by route and id.
+    in_flight: HashMap<(Frames, u64), Arc<AtomicBool>>,
+    // Where the requests streamed to calls still taking them go, by route
+    // and id.
+    request_streams: HashMap<(Frames, u64), Sender<Streamed>>,
+    // Flow control of the calls streaming responses, by route and id.
+    windows: HashMap<(Frames, u64), ResponseWindow>,
+    stream_timeout: Option<Duration>,
+    broker: Option<Peer>,
+}
+
+impl<D: Dispatch> Endpoint<Inline<D>> {
+    /// An endpoint running requests inline, on the thread which processes it.
+    /// Calls to streaming methods fail with `FailedPrecondition`; serve them
+    /// with a `ThreadPool`, see `with_executor`.
+    pub fn new(socket: zmq::Socket, dispatcher: D) -> Endpoint<Inline<D>> {
+        Endpoint::with_executor(socket, Inline::new(dispatcher))
+    }
+
+    pub fn dispatcher(&mut self) -> &mut D { self.executor.dispatcher() }
+}
+
+impl<E: Executor> Endpoint<E> {
+    pub fn with_executor(mut socket: zmq::Socket, executor: E) -> Endpoint<E> {
+        match socket.get_socket_type() {
+            Ok(zmq::ROUTER) => match socket.set_router_mandatory(true) {
+                Ok(()) => {},
+                Err(err) => warn!("server: cannot set ROUTER_MANDATORY, \
+                                   replies may be dropped: {}", err),
+            },
+            _ => {},
+        }
+        Endpoint {
+            socket: socket,
+            executor: executor,
+            send_queue: SendQueue::new(),
+            in_flight: HashMap::new(),
+            request_streams: HashMap::new(),
+            windows: HashMap::new(),
+            stream_timeout:
+                Some(Duration::seconds(DEFAULT_STREAM_TIMEOUT_SECS)),
+            broker: None,
+        }
+    }
+
+    /// Sets how long handlers wait for the next request streamed to their
+    /// call, or for credits, on top of its deadline, so that a client which
+    /// goes away without cancelling doesn't hold up a thread of the executor
+    /// forever. `None` waits until the deadline, if any.
+    pub fn set_stream_timeout(&mut self, timeout: Option<Duration>) {
+        self.stream_timeout = timeout;
+    }
+
+    pub fn poll_item<'b>(&self, events: i16) -> zmq::PollItem<'b> {
+        self.socket.as_poll_item(events)
+    }
+
+    /// See `Executor::poll_item`.
+    pub fn executor_poll_item<'b>(&self, events: i16)
+            -> Option<zmq::PollItem<'b>> {
+        self.executor.poll_item(events)
+    }
+
+    /// The queue of replies waiting for the socket to become writable.
+    pub fn send_queue(&self) -> SendQueue { self.send_queue.clone() }
+
+    /// The number of requests which are being handled, or whose replies have
+    /// not been sent yet, held back ones included.
+    pub fn in_flight(&self) -> uint {
+        self.in_flight.len() + self.send_queue.len()
+    }
+
+    /// For endpoints connected to a broker: checks that the broker is alive,
+    /// as the broker sends heartbeats to its workers, see `heartbeat`.
+    pub fn set_liveness(&mut self, liveness: Option<Liveness>) {
+        self.broker = liveness.map(Peer::new);
+    }
+
+    /// Checks that the broker was heard from within `Liveness::timeout`.
+    /// Schedule it with `Reactor::schedule_repeating` every
+    /// `Liveness::interval`.
+    ///
+    /// A dead broker, or a restarted one which doesn't know this worker, is
+    /// sent a ready announcement on every call until it answers.
+    pub fn heartbeat(&mut self) -> Result<(), Error> {
+        let alive = match self.broker {
+            Some(ref mut broker) => {
+                if broker.check() {
+                    warn!("server: broker stopped sending heartbeats");
+                }
+                broker.alive()
+            },
+            None => return Ok(()),
+        };
+        if alive { Ok(()) } else { self.send_ready() }
+    }
+
+    /// Flushes queued replies on `POLLOUT` and processes a request on
+    /// `POLLIN`.
+    pub fn handle_events(&mut self, revents: i16) -> Result<(), Error> {
+        if revents & zmq::POLLOUT != 0 {
+            try!(self.send_queue.flush(&mut self.socket));
+        }
+        if revents & zmq::POLLIN != 0 {
+            try!(self.process());
+        }
+        Ok(())
+    }
+
+    /// Receives a single request and hands it to the executor, sending the
+    /// replies it produces right away. Cancellations flag the request
+    /// they refer to, if it is still being handled, and no reply is sent for
+    /// it. Heartbeats are echoed back. Streamed requests and credits are
+    /// passed on to their call.
+    pub fn process(&mut self) -> Result<(), Error> {
+        let frames = try!(wire::recv_frames(&mut self.socket, 0));
+        match self.broker {
+            Some(ref mut broker) if broker.seen() => {
+                info!("server: broker is sending heartbeats again");
+            },
+            _ => {},
+        }
+        let (route, request) = try!(Envelope::decode(frames));
+        let key = (route.clone(), request.id);
+        match request.kind {
+            Request | StreamRequest => {},
+            Heartbeat => {
+                self.send_queue.push(request.encode(route));
+                return self.send_queue.flush(&mut self.socket);
+            },
+            Cancel => {
+                match self.in_flight.get(&key) {
+                    Some(cancelled) => cancelled.store(true, SeqCst),
+                    None => debug!("server: cancel for finished request {}",
+                                   request.id),
+                }
+                // Held back responses are dropped, and handlers waiting for
+                // requests or credits wake up, then find out they were
+                // cancelled.
+                self.windows.remove(&key);
+                self.request_streams.remove(&key);
+                return Ok(());
+            },
+            StreamItem => {
+                match self.request_streams.get(&key) {
+                    Some(sender) => {
+                        // The handler may be done with the requests already.
+                        let _ = sender.send_opt(Item(request.payload));
+                    },
+                    None => debug!("server: dropping request streamed to \
+                                    finished request {}", request.id),
+                }
+                return Ok(());
+            },
+            StreamEnd => {
+                match self.request_streams.remove(&key) {
+                    Some(sender) => { let _ = sender.send_opt(HalfClosed); },
+                    None => {},
+                }
+                return Ok(());
+            },
+            Credit => {
+                let credits = try!(request.credits());
+                match self.windows.get_mut(&key) {
+                    Some(window) => {
+                        window.window.grant(credits);
+                        // The handler may be done streaming already.
+                        let _ = window.producer.send_opt(credits);
+                    },
+                    None => return Ok(()),
+                }
+                return self.release(&key);
+            },
+            _ => return Err(Error::with_desc(NetworkError,
+                                             "expected request")),
+        }
+
+        let ctx = Context::new(request.budget.map(Deadline::after),
+                               request.metadata);
+        self.in_flight.insert(key.clone(), ctx.cancelled.clone());
+        let streaming = self.executor.streaming(request.method.as_slice());
+        let mut job = Job::new(route, request.id, request.method,
+                               request.payload, ctx);
+        if streaming {
+            let (producer, credits) = channel();
+            self.windows.insert(key.clone(), ResponseWindow::new(producer));
+            job = job.with_credits(credits, self.stream_timeout);
+        }
+        if request.kind == StreamRequest {
+            let (sender, incoming) = Incoming::new();
+            self.request_streams.insert(key, sender);
+            job = job.with_requests(incoming, self.stream_timeout);
+        }
+        for reply in self.executor.execute(job).into_iter() {
+            try!(self.send_reply(reply));
+        }
+        Ok(())
+    }
+
+    /// Tells the broker this endpoint is connected to that it can take
+    /// requests.
+    pub fn send_ready(&mut self) -> Result<(), Error> {
+        self.send_queue.push(Envelope::ready().encode(Vec::new()));
+        self.send_queue.flush(&mut self.socket)
+    }
+
+    /// Receives a single reply from the executor and sends it.
+    pub fn process_reply(&mut self) -> Result<(), Error> {
+        let reply = try!(self.executor.recv_reply());
+        self.send_reply(reply)
+    }
+
+    /// Sends a reply encoded by the executor, as is, once the credits of its
+    /// call allow.
+    fn send_reply(&mut self, reply: Frames) -> Result<(), Error> {
+        let (route, kind, id) = try!(Envelope::peek(reply.as_slice()));
+        let key = (route, id);
+        let cancelled = self.in_flight.get(&key)
+            .map_or(false, |cancelled| cancelled.load(SeqCst));
+        // Handlers taking streamed requests grant credits for them, mid-call.
+        let last = kind != StreamItem && kind != Credit;
+        if last {
+            // Requests streamed from now on are dropped.
+            self.request_streams.remove(&key);
+        }
+        if cancelled {
+            if last { self.finish(&key); }
+            return Ok(());
+        }
+        if kind != Credit && self.windows.contains_key(&key) {
+            // Streams are in flight until their last message, which waits
+            // behind the responses held back for lack of credits.
+            self.windows.get_mut(&key).unwrap().held.push_back((kind, reply));
+            return self.release(&key);
+        }
+        if last { self.finish(&key); }
+        self.send_queue.push(reply);
+        self.send_queue.flush(&mut self.socket)
+    }
+
+    /// Sends the responses of a call which its credits allow, and its last
+    /// message once they are all out.
+    fn release(&mut self, key: &(Frames, u64)) -> Result<(), Error> {
+        loop {
+            let (kind, reply) = match self.windows.get_mut(key) {
+                Some(window) => {
+                    let item = match window.held.front() {
+                        Some(&(ref kind, _)) => *kind == StreamItem,
+                        None => break,
+                    };
+                    if item && !window.window.take() { break; }
+                    window.held.pop_front().unwrap()
+                },
+                None => break,
+            };
+            if kind != StreamItem { self.finish(key); }
+            self.send_queue.push(reply);
+        }
+        self.send_queue.flush(&mut self.socket)
+    }
+
+    /// Forgets a call which sent its last message.
+    fn finish(&mut self, key: &(Frames, u64)) {
+        self.in_flight.remove(key);
+        self.windows.remove(key);
+        self.request_streams.remove(key);
+    }
+}
+
+/// Decodes `payload` with `codec`, runs `handler` on it and encodes the
+/// response. Fails with `InvalidArgument` if the payload can't be decoded.
+pub fn invoke<Req, Resp, C>(codec: &C,
+                            payload: &[u8],
+                            handler: |Req| -> Result<Resp, Error>)
+        -> Result<Vec<u8>, Error>
+        where C: Codec<Req> + Codec<Resp> {
+    let request = try!(codec::decode(codec, payload).map_err(malformed));
+    handler(request).and_then(|response| codec::encode(codec, &response))
+}
+
+/// Decodes `payload` with `codec` and runs the streaming `handler` on it,
+/// passing every response it sends to `emit` once encoded. Fails with
+/// `InvalidArgument` if the payload can't be decoded.
+pub fn invoke_stream<Req, Resp, C>(codec: &C,
+                                   ctx: &Context,
+                                   payload: &[u8],
+                                   emit: |Vec<u8>|,
+                                   handler: |Req, &mut Sink<Resp>|
+                                             -> Result<(), Error>)
+        -> Result<(), Error>
+        where C: Codec<Req> + Codec<Resp> {
+    let request = try!(codec::decode(codec, payload).map_err(malformed));
+    let mut sink = Sink {
+        ctx: ctx,
+        encode: |response: &Resp| codec::encode(codec, response),
+        emit: emit,
+    };
+    handler(request, &mut sink)
+}
+
+/// Runs `handler` on the requests of a call taking a stream of them, decoded
+/// with `codec`, and encodes the response.
+pub fn invoke_client_stream<Req, Resp, C>(codec: &C,
+                                          requests: &mut Requests,
+                                          handler: |&mut Source<Req>|
+                                                    -> Result<Resp, Error>)
+        -> Result<Vec<u8>, Error>
+        where C: Codec<Req> + Codec<Resp> {
+    let mut source = Source {
+        next: || requests.recv(),
+        decode: |payload: &[u8]| {
+            codec::decode(codec, payload).map_err(malformed)
+        },
+    };
+    handler(&mut source).and_then(|response| codec::encode(codec, &response))
+}
+
+/// Runs `handler` on the requests of a call taking a stream of them, as in
+/// `invoke_client_stream`, passing the responses it sends to `emit` once
+/// encoded, as in `invoke_stream`.
+pub fn invoke_bidi<Req, Resp, C>(codec: &C,
+                                 ctx: &Context,
+                                 requests: &mut Requests,
+                                 emit: |Vec<u8>|,
+                                 handler: |&mut Source<Req>, &mut Sink<Resp>|
+                                           -> Result<(), Error>)
+        -> Result<(), Error>
+        where C: Codec<Req> + Codec<Resp> {
+    let mut source = Source {
+        next: || requests.recv(),
+        decode: |payload: &[u8]| {
+            codec::decode(codec, payload).map_err(malformed)
+        },
+    };
+    let mut sink = Sink {
+        ctx: ctx,
+        encode: |response: &Resp| codec::encode(codec, response),
+        emit: emit,
+    };
+    handler(&mut source, &mut sink)
+}
+
+fn malformed(err: Error) -> Error {
+    Error::with_desc(InvalidArgument, "malformed request").caused_by(err)
+}
+
+pub fn unknown_method(method: &str) -> Error {
+    let method = method.to_string();
+    Error::with_lazy_desc(Unimplemented,
+                          proc() format!("unknown method '{}'", method))
+}
+
+#[cfg(test)]
+mod test {
+    use deadline::Deadline;
+    use error::DeadlineExceeded;
+    use flow::INITIAL_CREDITS;
+    use std::time::Duration;
+    use super::{HalfClosed, Incoming, Item, Requests};
+
+    #[test]
+    fn test_requests() {
+        let (sender, incoming) = Incoming::new();
+        let mut requests = Requests::new(incoming, None, None, |_| {});
+        sender.send(Item(vec![1]));
+        sender.send(HalfClosed);
+        assert_eq!(requests.recv().unwrap().ok(), Some(vec![1]));
+        assert!(requests.recv().is_none());
+        assert!(requests.recv().is_none());
+    }
+
+    #[test]
+    fn test_requests_credits() {
+        let (sender, incoming) = Incoming::new();
+        let mut granted = Vec::new();
+        {
+            let mut requests = Requests::new(incoming, None, None,
+                                             |credits| granted.push(credits));
+            for _ in range(0, INITIAL_CREDITS) {
+                sender.send(Item(vec![1]));
+                assert!(requests.recv().unwrap().is_ok());
+            }
+        }
+        // Taking half of the window grants it back.
+        assert_eq!(granted, vec![INITIAL_CREDITS / 2, INITIAL_CREDITS / 2]);
+    }
+
+    #[test]
+    fn test_requests_timeout() {
+        let (sender, incoming) = Incoming::new();
+        let deadline = Deadline::after(Duration::milliseconds(10));
+        let mut requests =
+            Requests::new(incoming, Some(deadline), None, |_| {});
+        sender.send(Item(vec![1]));
+        assert_eq!(requests.recv().unwrap().ok(), Some(vec![1]));
+        // The client never half-closes the stream.
+        assert_eq!(requests.recv().unwrap().err().unwrap().code(),
+                   DeadlineExceeded);
+        assert!(deadline.expired());
+        assert!(requests.recv().is_none());
+
+        // Nor sends anything, for a call without a deadline.
+        let (_sender, incoming) = Incoming::new();
+        let mut requests = Requests::new(
+            incoming, None, Some(Duration::milliseconds(5)), |_| {});
+        assert_eq!(requests.recv().unwrap().err().unwrap().code(),
+                   DeadlineExceeded);
+    }
+}
@@ -0,0 +1,614 @@
+#![macro_escape]
+
+/// Declares a service, generating a module named after it which contains:
+///
+///  * `Server`, the trait implemented by the service;
+///  * `Dispatcher<S, C>`, which routes requests to a `Server` and can be
+///    served with a `server::Endpoint`;
+///  * `Client<C>`, whose methods return `Future`-s fulfilled by a
+///    `client::Channel<C>`.
+///
+/// `C` is the `Codec` used for payloads and must be able to encode every
+/// request and response type. Request and response types are resolved in the
+/// invoking module.
+///
+/// Methods marked `#[idempotent]` are safe to call more than once, and the
+/// client retries them according to the retry policy of its channel. No other
+/// attribute is accepted.
+///
+/// Streaming methods, declared with `stream fn`, respond with any number of
+/// values: the server sends them to a `server::Sink` and the client receives
+/// a `stream::Stream`. Methods declared with `client_stream fn` take any
+/// number of requests instead: the client sends them to a
+/// `client::RequestSink`, closing it when done, and the server iterates over
+/// a `server::Source`. `bidi_stream fn` methods do both.
+///
+/// Methods must be declared in that order: every `fn`, then every
+/// `stream fn`, `client_stream fn` and `bidi_stream fn`, since the macro
+/// can't match the kinds interleaved.
+///
+/// ```ignore
+/// zuffy_service! {
+///     service Calc {
+///         fn add(AddReq) -> AddResp;
+///         #[idempotent]
+///         fn get(GetReq) -> GetResp;
+///         stream fn list(ListReq) -> GetResp;
+///         client_stream fn put_all(PutReq) -> PutResp;
+///         bidi_stream fn watch(WatchReq) -> GetResp;
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! zuffy_service(
+    (service $name:ident {
+        $($(#[$flag:ident])* fn $method:ident($req:ty) -> $resp:ty;)*
+        $(stream fn $smethod:ident($sreq:ty) -> $sresp:ty;)*
+        $(client_stream fn $cmethod:ident($creq:ty) -> $cresp:ty;)*
+        $(bidi_stream fn $bmethod:ident($breq:ty) -> $bresp:ty;)*
+    }) => (
+        #[allow(non_snake_case, dead_code)]
+        pub mod $name {
+            #![allow(unused_imports)]
+            use super::*;
+
+            /// Whether `method` is marked `#[idempotent]`.
+            pub fn idempotent(method: &str) -> bool {
+                $(
+                    if method == stringify!($method) {
+                        return zuffy_idempotent!($($flag)*);
+                    }
+                )*
+                false
+            }
+
+            pub trait Server {
+                $(
+                    fn $method(&mut self, ctx: &::server::Context,
+                               request: $req)
+                        -> Result<$resp, ::error::Error>;
+                )*
+                $(
+                    fn $smethod(&mut self, ctx: &::server::Context,
+                                request: $sreq,
+                                sink: &mut ::server::Sink<$sresp>)
+                        -> Result<(), ::error::Error>;
+                )*
+                $(
+                    fn $cmethod(&mut self, ctx: &::server::Context,
+                                requests: &mut ::server::Source<$creq>)
+                        -> Result<$cresp, ::error::Error>;
+                )*
+                $(
+                    fn $bmethod(&mut self, ctx: &::server::Context,
+                                requests: &mut ::server::Source<$breq>,
+                                sink: &mut ::server::Sink<$bresp>)
+                        -> Result<(), ::error::Error>;
+                )*
+            }
+
+            #[deriving(Clone)]
+            pub struct Dispatcher<S, C> {
+                server: S,
+                codec: C,
+            }
+
+            impl<S: Server, C> Dispatcher<S, C> {
+                pub fn new(server: S, codec: C) -> Dispatcher<S, C> {
+                    Dispatcher {
+                        server: server,
+                        codec: codec,
+                    }
+                }
+
+                pub fn server(&mut self) -> &mut S { &mut self.server }
+            }
+
+            impl<S: Server, C> ::server::Dispatch for Dispatcher<S, C>
+                    where C: $(::codec::Codec<$req> + ::codec::Codec<$resp> +)*
+                             $(::codec::Codec<$sreq> +
+                               ::codec::Codec<$sresp> +)*
+                             $(::codec::Codec<$creq> +
+                               ::codec::Codec<$cresp> +)*
+                             $(::codec::Codec<$breq> +
+                               ::codec::Codec<$bresp> +)*
+                             'static {
+                fn dispatch(&mut self, ctx: &::server::Context,
+                            method: &str, payload: &[u8])
+                        -> Result<Vec<u8>, ::error::Error> {
+                    let server = &mut self.server;
+                    $(
+                        if method == stringify!($method) {
+                            return ::server::invoke(
+                                &self.codec, payload,
+                                |request| server.$method(ctx, request));
+                        }
+                    )*
+                    Err(::server::unknown_method(method))
+                }
+
+                // These are unused when the service has no methods of the
+                // kind.
+                #[allow(unused_variables)]
+                fn streaming(&self, method: &str) -> bool {
+                    $(
+                        if method == stringify!($smethod) { return true; }
+                    )*
+                    $(
+                        if method == stringify!($bmethod) { return true; }
+                    )*
+                    false
+                }
+
+                #[allow(unused_variables)]
+                fn takes_stream(&self, method: &str) -> bool {
+                    $(
+                        if method == stringify!($cmethod) { return true; }
+                    )*
+                    $(
+                        if method == stringify!($bmethod) { return true; }
+                    )*
+                    false
+                }
+
+                #[allow(unused_variables)]
+                fn dispatch_stream(&mut self, ctx: &::server::Context,
+                                   method: &str, payload: &[u8],
+                                   emit: |Vec<u8>|)
+                        -> Result<(), ::error::Error> {
+                    let server = &mut self.server;
+                    $(
+                        if method == stringify!($smethod) {
+                            return ::server::invoke_stream(
+                                &self.codec, ctx, payload, emit,
+                                |request, sink| {
+                                    server.$smethod(ctx, request, sink)
+                                });
+                        }
+                    )*
+                    Err(::server::unknown_method(method))
+                }
+
+                #[allow(unused_variables)]
+                fn dispatch_client_stream(
+                        &mut self, ctx: &::server::Context, method: &str,
+                        requests: &mut ::server::Requests)
+                        -> Result<Vec<u8>, ::error::Error> {
+                    let server = &mut self.server;
+                    $(
+                        if method == stringify!($cmethod) {
+                            return ::server::invoke_client_stream(
+                                &self.codec, requests,
+                                |source| server.$cmethod(ctx, source));
+                        }
+                    )*
+                    Err(::server::unknown_method(method))
+                }
+
+                #[allow(unused_variables)]
+                fn dispatch_bidi(&mut self, ctx: &::server::Context,
+                                 method: &str,
+                                 requests: &mut ::server::Requests,
+                                 emit: |Vec<u8>|)
+                        -> Result<(), ::error::Error> {
+                    let server = &mut self.server;
+                    $(
+                        if method == stringify!($bmethod) {
+                            return ::server::invoke_bidi(
+                                &self.codec, ctx, requests, emit,
+                                |source, sink| {
+                                    server.$bmethod(ctx, source, sink)
+                                });
+                        }
+                    )*
+                    Err(::server::unknown_method(method))
+                }
+            }
+
+            pub struct Client<C> {
+                channel: ::client::Channel<C>,
+            }
+
+            impl<C> Client<C>
+                    where C: $(::codec::Codec<$req> + ::codec::Codec<$resp> +)*
+                             $(::codec::Codec<$sreq> +
+                               ::codec::Codec<$sresp> +)*
+                             $(::codec::Codec<$creq> +
+                               ::codec::Codec<$cresp> +)*
+                             $(::codec::Codec<$breq> +
+                               ::codec::Codec<$bresp> +)*
+                             Clone + 'static {
+                pub fn new(channel: ::client::Channel<C>) -> Client<C> {
+                    Client { channel: channel }
+                }
+
+                pub fn channel(&mut self) -> &mut ::client::Channel<C> {
+                    &mut self.channel
+                }
+
+                /// See `Channel::set_retry_policy`.
+                pub fn set_retry_policy(
+                        &mut self, policy: Option<::retry::RetryPolicy>) {
+                    self.channel.set_retry_policy(policy);
+                }
+
+                /// Sets a deadline for the next call only.
+                pub fn deadline(&mut self, deadline: ::deadline::Deadline)
+                        -> &mut Client<C> {
+                    self.channel.deadline(deadline);
+                    self
+                }
+
+                /// Gives the next call the deadline of the request being
+                /// handled in `ctx`.
+                pub fn within(&mut self, ctx: &::server::Context)
+                        -> &mut Client<C> {
+                    self.channel.within(ctx);
+                    self
+                }
+
+                $(
+                    pub fn $method(&mut self, request: $req)
+                            -> ::future::Future<Result<$resp, ::error::Error>,
+                                                ::client::Channel<C>> {
+                        if idempotent(stringify!($method)) {
+                            self.channel.idempotent();
+                        }
+                        self.channel.call(stringify!($method), &request)
+                    }
+                )*
+
+                $(
+                    pub fn $smethod(&mut self, request: $sreq)
+                            -> ::stream::Stream<$sresp> {
+                        self.channel.call_stream(stringify!($smethod),
+                                                 &request)
+                    }
+                )*
+
+                $(
+                    pub fn $cmethod(&mut self)
+                            -> (::client::RequestSink<$creq, C>,
+                                ::future::AsyncFuture<
+                                    Result<$cresp, ::error::Error>>) {
+                        self.channel.call_client_stream(stringify!($cmethod))
+                    }
+                )*
+
+                $(
+                    pub fn $bmethod(&mut self)
+                            -> (::client::RequestSink<$breq, C>,
+                                ::stream::Stream<$bresp>) {
+                        self.channel.call_bidi_stream(stringify!($bmethod))
+                    }
+                )*
+            }
+        }
+    )
+)
+
+/// Whether the attributes of a method in `zuffy_service!` mark it
+/// idempotent, rejecting any other attribute.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! zuffy_idempotent(
+    () => (false);
+    (idempotent) => (true);
+)
+
+#[cfg(test)]
+mod test {
+    use client::Channel;
+    use codec::{BinaryCodec, JsonCodec};
+    use deadline::Deadline;
+    use error::{Error, DeadlineExceeded, InternalServerError, InvalidArgument};
+    use error::Unimplemented;
+    use executor::ThreadPool;
+    use flow::INITIAL_CREDITS;
+    use server::{Context, Dispatch, Endpoint, Sink, Source};
+    use std::time::Duration;
+    use wire::Metadata;
+    use zmq;
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    pub struct AddReq {
+        pub a: int,
+        pub b: int,
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    pub struct AddResp {
+        pub sum: int,
+    }
+
+    zuffy_service! {
+        service Calc {
+            fn add(AddReq) -> AddResp;
+            #[idempotent]
+            fn negate(int) -> int;
+            stream fn count(int) -> int;
+            client_stream fn sum(int) -> int;
+            bidi_stream fn running_sum(int) -> int;
+        }
+    }
+
+    #[deriving(Clone)]
+    struct CalcServer;
+    impl Calc::Server for CalcServer {
+        fn add(&mut self, _ctx: &Context, request: AddReq)
+                -> Result<AddResp, Error> {
+            Ok(AddResp { sum: request.a + request.b })
+        }
+
+        fn negate(&mut self, _ctx: &Context, request: int)
+                -> Result<int, Error> {
+            if request == 0 {
+                Err(Error::with_desc(InternalServerError, "zero"))
+            } else {
+                Ok(-request)
+            }
+        }
+
+        fn count(&mut self, _ctx: &Context, request: int,
+                 sink: &mut Sink<int>) -> Result<(), Error> {
+            for i in range(0, request) {
+                try!(sink.send(&i));
+            }
+            Ok(())
+        }
+
+        fn sum(&mut self, _ctx: &Context, requests: &mut Source<int>)
+                -> Result<int, Error> {
+            let mut sum = 0;
+            for request in requests.by_ref() {
+                sum += try!(request);
+            }
+            Ok(sum)
+        }
+
+        fn running_sum(&mut self, _ctx: &Context, requests: &mut Source<int>,
+                       sink: &mut Sink<int>) -> Result<(), Error> {
+            let mut sum = 0;
+            for request in requests.by_ref() {
+                sum += try!(request);
+                try!(sink.send(&sum));
+            }
+            Ok(())
+        }
+    }
+
+    fn context() -> Context { Context::new(None, Metadata::new()) }
+
+    #[test]
+    fn test_dispatch_unknown_method() {
+        let mut dispatcher = Calc::Dispatcher::new(CalcServer, JsonCodec);
+        let err = dispatcher.dispatch(&context(), "multiply", b"{}")
+            .err().unwrap();
+        assert_eq!(err.code(), Unimplemented);
+        assert_eq!(err.desc(), "unknown method 'multiply'");
+    }
+
+    #[test]
+    fn test_idempotent() {
+        assert!(!Calc::idempotent("add"));
+        assert!(Calc::idempotent("negate"));
+        assert!(!Calc::idempotent("multiply"));
+    }
+
+    #[test]
+    fn test_streaming() {
+        let dispatcher = Calc::Dispatcher::new(CalcServer, JsonCodec);
+        assert!(dispatcher.streaming("count"));
+        assert!(!dispatcher.streaming("add"));
+        assert!(!dispatcher.streaming("sum"));
+        assert!(dispatcher.takes_stream("sum"));
+        assert!(dispatcher.streaming("running_sum"));
+        assert!(dispatcher.takes_stream("running_sum"));
+        assert!(!dispatcher.takes_stream("count"));
+    }
+
+    #[test]
+    fn test_dispatch_bad_payload() {
+        let mut dispatcher = Calc::Dispatcher::new(CalcServer, JsonCodec);
+        let err = dispatcher.dispatch(&context(), "add", b"[1, 2]")
+            .err().unwrap();
+        assert_eq!(err.code(), InvalidArgument);
+        assert_eq!(err.cause().unwrap().code(), InternalServerError);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut ctx = zmq::Context::new();
+        let mut socket = ctx.socket(zmq::ROUTER).unwrap();
+        socket.bind("inproc://zuffy-service-test").unwrap();
+        let mut endpoint = Endpoint::new(
+            socket, Calc::Dispatcher::new(CalcServer, BinaryCodec));
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        let sum = client.add(AddReq { a: 2, b: 3 }).async();
+        let negated = client.negate(0).async();
+        assert_eq!(client.channel().pending(), 2);
+
+        endpoint.process().unwrap();
+        endpoint.process().unwrap();
+        client.channel().process().unwrap();
+        client.channel().process().unwrap();
+        assert!(sum.ready());
+        assert!(negated.ready());
+
+        sum.map(proc(sum) {
+            assert_eq!(sum.ok().unwrap(), AddResp { sum: 5 });
+        });
+        negated.map(proc(negated) {
+            assert_eq!(negated.err().unwrap().desc(), "zero");
+        });
+    }
+
+    // An endpoint bound to `address` serving `Calc` on a thread pool, as
+    // streaming calls need.
+    fn pool_endpoint(ctx: &mut zmq::Context, address: &str)
+            -> Endpoint<ThreadPool> {
+        let mut socket = ctx.socket(zmq::ROUTER).unwrap();
+        socket.bind(address).unwrap();
+        let dispatcher = Calc::Dispatcher::new(CalcServer, BinaryCodec);
+        let pool = ThreadPool::new(ctx, 1, dispatcher).unwrap();
+        Endpoint::with_executor(socket, pool)
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let mut ctx = zmq::Context::new();
+        let mut endpoint =
+            pool_endpoint(&mut ctx, "inproc://zuffy-service-stream-test");
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-stream-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        let sum = client.count(4)
+            .filter(|&mut: i: &int| *i % 2 == 1)
+            .fold(0, |&mut: sum: int, i: int| sum + i);
+        endpoint.process().unwrap();
+        // Four items and the end of the stream.
+        for _ in range(0u, 5) {
+            endpoint.process_reply().unwrap();
+            client.channel().process().unwrap();
+        }
+        assert_eq!(endpoint.in_flight(), 0);
+        assert_eq!(client.channel().pending(), 0);
+        sum.map(proc(sum) assert_eq!(sum.ok(), Some(4)));
+    }
+
+    #[test]
+    fn test_client_stream_round_trip() {
+        let mut ctx = zmq::Context::new();
+        let mut endpoint = pool_endpoint(
+            &mut ctx, "inproc://zuffy-service-client-stream-test");
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-client-stream-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        let (mut sink, sum) = client.sum();
+        sink.send(&1).unwrap();
+        sink.send(&2).unwrap();
+        sink.close();
+        client.channel().handle_events(zmq::POLLOUT).unwrap();
+
+        // The call, its requests and the half-close.
+        for _ in range(0u, 4) {
+            endpoint.process().unwrap();
+        }
+        assert_eq!(endpoint.in_flight(), 1);
+        endpoint.process_reply().unwrap();
+        assert_eq!(endpoint.in_flight(), 0);
+        client.channel().process().unwrap();
+        sum.map(proc(sum) assert_eq!(sum.ok(), Some(3)));
+    }
+
+    #[test]
+    fn test_bidi_stream_round_trip() {
+        let mut ctx = zmq::Context::new();
+        let mut endpoint =
+            pool_endpoint(&mut ctx, "inproc://zuffy-service-bidi-test");
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-bidi-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        let (mut sink, sums) = client.running_sum();
+        let sums = sums.collect();
+        sink.send(&1).unwrap();
+        sink.send(&2).unwrap();
+        // Dropping the sink half-closes the requests too.
+        drop(sink);
+        client.channel().handle_events(zmq::POLLOUT).unwrap();
+        for _ in range(0u, 4) {
+            endpoint.process().unwrap();
+        }
+        // Two sums and the end of the stream.
+        for _ in range(0u, 3) {
+            endpoint.process_reply().unwrap();
+            client.channel().process().unwrap();
+        }
+        assert_eq!(client.channel().pending(), 0);
+        sums.map(proc(sums) assert_eq!(sums.ok().unwrap(), vec![1, 3]));
+    }
+
+    #[test]
+    fn test_stream_window() {
+        let mut ctx = zmq::Context::new();
+        let mut endpoint =
+            pool_endpoint(&mut ctx, "inproc://zuffy-service-window-test");
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-window-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        let count = INITIAL_CREDITS as int + 4;
+        let values = client.count(count).collect();
+        endpoint.process().unwrap();
+        for _ in range(0, INITIAL_CREDITS) {
+            endpoint.process_reply().unwrap();
+        }
+        // The handler waits for the client's credits.
+        {
+            let mut items = [endpoint.executor_poll_item(zmq::POLLIN)
+                                 .unwrap()];
+            assert_eq!(zmq::poll(&mut items, 50).unwrap(), 0);
+        }
+        assert_eq!(endpoint.in_flight(), 1);
+        for _ in range(0, INITIAL_CREDITS) {
+            client.channel().process().unwrap();
+        }
+
+        // Taking the responses granted credits for the rest, in two batches.
+        client.channel().handle_events(zmq::POLLOUT).unwrap();
+        for _ in range(0u, 2) {
+            endpoint.process().unwrap();
+        }
+        // The other four items and the end of the stream.
+        for _ in range(0u, 5) {
+            endpoint.process_reply().unwrap();
+            client.channel().process().unwrap();
+        }
+        assert_eq!(endpoint.in_flight(), 0);
+        assert_eq!(client.channel().pending(), 0);
+        let expected: Vec<int> = range(0, count).collect();
+        values.map(proc(values) assert_eq!(values.ok(), Some(expected)));
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        let mut ctx = zmq::Context::new();
+        let mut socket = ctx.socket(zmq::ROUTER).unwrap();
+        socket.bind("inproc://zuffy-service-deadline-test").unwrap();
+        let mut endpoint = Endpoint::new(
+            socket, Calc::Dispatcher::new(CalcServer, BinaryCodec));
+
+        let mut socket = ctx.socket(zmq::DEALER).unwrap();
+        socket.connect("inproc://zuffy-service-deadline-test").unwrap();
+        let mut client = Calc::Client::new(Channel::new(socket, BinaryCodec));
+
+        // Nobody is processing requests, so the call times out.
+        let deadline = Deadline::after(Duration::milliseconds(10));
+        let result =
+            client.deadline(deadline).add(AddReq { a: 1, b: 1 }).sync();
+        assert_eq!(result.err().unwrap().code(), DeadlineExceeded);
+        assert_eq!(client.channel().pending(), 0);
+
+        // The deadline only applied to the previous call.
+        let sum = client.add(AddReq { a: 1, b: 1 }).async();
+        assert_eq!(client.channel().next_expiry(), None);
+
+        // The server then replies to both, the stale reply is dropped.
+        endpoint.process().unwrap();
+        endpoint.process().unwrap();
+        client.channel().process().unwrap();
+        client.channel().process().unwrap();
+        assert!(sum.ready());
+    }
+}
@@ -0,0 +1,299 @@
+use error::{Error, InternalServerError};
+use future::{AsyncFuture, CancelToken, Future};
+use std::cell::RefCell;
+use std::collections::RingBuf;
+use std::rc::Rc;
+
+/// An asynchronous sequence of values ending either normally or with an
+/// error; the result of a call to a streaming method.
+///
+/// Values which arrive before the stream is consumed are buffered, and
+/// consuming it with `map`, `filter`, `fold` or `collect` hands them over
+/// right away. Dropping a stream which was neither consumed nor ended
+/// cancels it.
+pub struct Stream<T> {
+    state: StateRef<T>,
+    token: CancelToken,
+    consumed: bool,
+}
+
+/// The end of a stream which produces its values.
+pub struct StreamSender<T> {
+    state: StateRef<T>,
+    token: CancelToken,
+}
+
+enum Event<T> {
+    Next(T),
+    End(Result<(), Error>),
+}
+
+type Consumer<T> = Box<FnMut(Event<T>) + 'static>;
+
+struct State<T> {
+    // Events waiting for a consumer, or for the consumer to return.
+    events: RingBuf<Event<T>>,
+    consumer: Option<Consumer<T>>,
+    on_taken: Option<Box<FnMut() + 'static>>,
+    ended: bool,
+}
+
+type StateRef<T> = Rc<RefCell<State<T>>>;
+
+impl<T: 'static> Stream<T> {
+    pub fn new_with_sender() -> (Stream<T>, StreamSender<T>) {
+        Stream::with_token(CancelToken::new())
+    }
+
+    fn with_token(token: CancelToken) -> (Stream<T>, StreamSender<T>) {
+        let state = Rc::new(RefCell::new(State {
+            events: RingBuf::new(),
+            consumer: None,
+            on_taken: None,
+            ended: false,
+        }));
+        (Stream { state: state.clone(), token: token.clone(), consumed: false },
+         StreamSender { state: state, token: token })
+    }
+
+    /// Stops the stream, cancelling the token shared with its sender so the
+    /// call producing it is cancelled as well. Buffered values are dropped.
+    pub fn cancel(self) {
+        self.token.cancel();
+        let mut state = self.state.borrow_mut();
+        state.events.clear();
+        state.consumer = None;
+    }
+
+    pub fn map<U: 'static, F>(self, mut through: F) -> Stream<U>
+            where F: FnMut(T) -> U + 'static {
+        let (stream, sender) = Stream::with_token(self.token.clone());
+        self.consume(box move |&mut: event: Event<T>| {
+            match event {
+                Next(value) => sender.send(through.call_mut((value,))),
+                End(result) => sender.finish(result),
+            }
+        });
+        stream
+    }
+
+    pub fn filter<F>(self, mut predicate: F) -> Stream<T>
+            where F: FnMut(&T) -> bool + 'static {
+        let (stream, sender) = Stream::with_token(self.token.clone());
+        self.consume(box move |&mut: event: Event<T>| {
+            match event {
+                Next(value) => {
+                    if predicate.call_mut((&value,)) { sender.send(value); }
+                },
+                End(result) => sender.finish(result),
+            }
+        });
+        stream
+    }
+
+    /// Combines the values with `through`, starting from `init`. Resolves
+    /// once the stream ends, with the error which ended it if any.
+    /// Cancelling the future cancels the stream.
+    pub fn fold<A: 'static, F>(self, init: A, mut through: F)
+            -> AsyncFuture<Result<A, Error>>
+            where F: FnMut(A, T) -> A + 'static {
+        let (future, promise) = Future::new_with_promise();
+        let token = self.token.clone();
+        promise.cancel_token().on_cancel(proc() token.cancel());
+        let mut folding = Some((init, promise));
+        self.consume(box move |&mut: event: Event<T>| {
+            let (acc, promise) = match folding.take() {
+                Some(folding) => folding,
+                None => return,
+            };
+            match event {
+                Next(value) => {
+                    folding = Some((through.call_mut((acc, value)), promise));
+                },
+                End(result) => promise.fulfill(result.map(|()| acc)),
+            }
+        });
+        future.async()
+    }
+
+    /// Gathers all the values, see `fold`.
+    pub fn collect(self) -> AsyncFuture<Result<Vec<T>, Error>> {
+        self.fold(Vec::new(), |&mut: mut values: Vec<T>, value: T| {
+            values.push(value);
+            values
+        })
+    }
+
+    fn consume(mut self, consumer: Consumer<T>) {
+        self.consumed = true;
+        self.state.borrow_mut().consumer = Some(consumer);
+        drain(&self.state);
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for Stream<T> {
+    fn drop(&mut self) {
+        if !self.consumed && !self.state.borrow().ended {
+            self.token.cancel();
+        }
+    }
+}
+
+impl<T> StreamSender<T> {
+    /// The token through which the stream's owner may cancel it.
+    pub fn cancel_token(&self) -> CancelToken { self.token.clone() }
+
+    /// Runs `callback` whenever the consumer took a value, so that the
+    /// producer may be let to send more. Values buffered while the stream is
+    /// not consumed are not taken yet.
+    pub fn on_taken(&self, callback: Box<FnMut() + 'static>) {
+        self.state.borrow_mut().on_taken = Some(callback);
+    }
+
+    /// Passes on `value`; does nothing if the stream was cancelled or ended.
+    pub fn send(&self, value: T) {
+        if self.token.is_cancelled() || self.state.borrow().ended { return; }
+        self.push(Next(value));
+    }
+
+    /// Ends the stream; does nothing if it was cancelled or already ended.
+    pub fn finish(&self, result: Result<(), Error>) {
+        if self.token.is_cancelled() || self.state.borrow().ended { return; }
+        self.state.borrow_mut().ended = true;
+        self.push(End(result));
+    }
+
+    fn push(&self, event: Event<T>) {
+        self.state.borrow_mut().events.push_back(event);
+        drain(&self.state);
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        self.finish(Err(Error::with_desc(InternalServerError,
+                                         "stream dropped before its end")));
+    }
+}
+
+/// Hands the buffered events to the consumer, if there is one. The consumer
+/// is taken out while it runs, so events it causes are queued and handed
+/// over by the outer call rather than re-entering it.
+fn drain<T>(state: &StateRef<T>) {
+    loop {
+        let (mut consumer, event) = {
+            let mut state = state.borrow_mut();
+            if state.consumer.is_none() { return; }
+            match state.events.pop_front() {
+                Some(event) => (state.consumer.take().unwrap(), event),
+                None => return,
+            }
+        };
+        let taken = match event { Next(_) => true, End(_) => false };
+        consumer.call_mut((event,));
+        state.borrow_mut().consumer = Some(consumer);
+        if !taken { continue; }
+        let on_taken = state.borrow_mut().on_taken.take();
+        match on_taken {
+            Some(mut on_taken) => {
+                on_taken.call_mut(());
+                state.borrow_mut().on_taken = Some(on_taken);
+            },
+            None => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Stream;
+    use error::{Error, NotFound};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_combinators() {
+        let (stream, sender) = Stream::new_with_sender();
+        // Values sent before the stream is consumed are buffered.
+        sender.send(1i);
+        sender.send(2);
+        let sum = stream.filter(|&mut: value: &int| *value != 3)
+                        .map(|&mut: value: int| value * 10)
+                        .fold(0, |&mut: sum: int, value: int| sum + value);
+        sender.send(3);
+        sender.send(4);
+        assert!(!sum.ready());
+        sender.finish(Ok(()));
+        assert!(sum.ready());
+        sum.map(proc(sum) assert_eq!(sum.ok(), Some(70)));
+    }
+
+    #[test]
+    fn test_collect_error() {
+        let (stream, sender) = Stream::new_with_sender();
+        let values = stream.collect();
+        sender.send(1i);
+        sender.finish(Err(Error::new(NotFound)));
+        // Values after the end are dropped.
+        sender.send(2);
+        values.map(proc(values) {
+            assert_eq!(values.err().unwrap().code(), NotFound);
+        });
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (stream, sender) = Stream::<int>::new_with_sender();
+        let values = stream.map(|&mut: value: int| value + 1).collect();
+        assert!(!sender.cancel_token().is_cancelled());
+        values.cancel();
+        assert!(sender.cancel_token().is_cancelled());
+        sender.send(1);
+    }
+
+    #[test]
+    fn test_drop() {
+        let (stream, sender) = Stream::<int>::new_with_sender();
+        let values = stream.collect();
+        drop(values);
+        assert!(!sender.cancel_token().is_cancelled());
+
+        let (stream, sender) = Stream::<int>::new_with_sender();
+        sender.finish(Ok(()));
+        drop(stream);
+        assert!(!sender.cancel_token().is_cancelled());
+
+        let (stream, sender) = Stream::<int>::new_with_sender();
+        drop(stream);
+        assert!(sender.cancel_token().is_cancelled());
+    }
+
+    #[test]
+    fn test_on_taken() {
+        let (stream, sender) = Stream::new_with_sender();
+        let taken = Rc::new(Cell::new(0u));
+        let counter = taken.clone();
+        sender.on_taken(box move |&mut:| counter.set(counter.get() + 1));
+        sender.send(1i);
+        sender.send(2);
+        // Buffered values only count once consumed.
+        assert_eq!(taken.get(), 0);
+        let values = stream.collect();
+        assert_eq!(taken.get(), 2);
+        sender.send(3);
+        sender.finish(Ok(()));
+        assert_eq!(taken.get(), 3);
+        values.map(proc(values) {
+            assert_eq!(values.ok().unwrap(), vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_dropped_sender() {
+        let (stream, sender) = Stream::<int>::new_with_sender();
+        drop(sender);
+        stream.collect().map(proc(values) assert!(values.is_err()));
+    }
+}
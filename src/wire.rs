@@ -0,0 +1,738 @@
+use codec::{mod, BinaryCodec};
+use error::{Error, ErrorCode, InternalServerError, NetworkError};
+use error::{FieldViolation, Info, RetryAfter};
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::RingBuf;
+use std::rc::Rc;
+use std::slice::Items;
+use std::time::Duration;
+use std::{i64, u32};
+use zmq;
+
+pub type Frames = Vec<Vec<u8>>;
+
+pub const PROTOCOL_VERSION: u8 = 7;
+
+/// Number of frames at the end of a message which make up the envelope. Any
+/// frames before them are routing identities added by ROUTER sockets.
+pub const ENVELOPE_FRAMES: uint = 4;
+
+const HEADER_LEN: uint = 14;
+
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Kind {
+    Request,
+    Reply,
+    ErrorReply,
+    // Tells the server the caller no longer waits for the reply to `id`.
+    Cancel,
+    // Sent by workers to a broker when they are ready to take requests.
+    Ready,
+    // Sent periodically to check that the peer is alive, and echoed back.
+    Heartbeat,
+    // One of the responses to a call to a streaming method, or one of the
+    // requests of a call taking a stream of them.
+    StreamItem,
+    // Ends the responses to a call to a streaming method. Streams which fail
+    // end with an `ErrorReply` instead. Sent by the client, it half-closes
+    // the requests of a call taking a stream of them.
+    StreamEnd,
+    // Like `Request`, for methods taking a stream of requests, which follow
+    // as `StreamItem`-s. The payload is empty.
+    StreamRequest,
+    // Lets the other end send more `StreamItem`-s of stream `id`, see
+    // `flow`.
+    Credit,
+}
+
+impl Kind {
+    fn to_byte(&self) -> u8 {
+        match *self {
+            Request => 0,
+            Reply => 1,
+            ErrorReply => 2,
+            Cancel => 3,
+            Ready => 4,
+            Heartbeat => 5,
+            StreamItem => 6,
+            StreamEnd => 7,
+            StreamRequest => 8,
+            Credit => 9,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Kind> {
+        match byte {
+            0 => Some(Request),
+            1 => Some(Reply),
+            2 => Some(ErrorReply),
+            3 => Some(Cancel),
+            4 => Some(Ready),
+            5 => Some(Heartbeat),
+            6 => Some(StreamItem),
+            7 => Some(StreamEnd),
+            8 => Some(StreamRequest),
+            9 => Some(Credit),
+            _ => None,
+        }
+    }
+}
+
+/// String key-value pairs carried alongside every message.
+#[deriving(Clone, PartialEq, Show, Default, Encodable, Decodable)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata { entries: Vec::new() }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|&&(ref k, _)| k.as_slice() == key)
+            .map(|&(_, ref v)| v.as_slice())
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        match self.entries.iter_mut().find(|&&(ref k, _)| *k == key) {
+            Some(&(_, ref mut v)) => { *v = value; return; },
+            None => {},
+        }
+        self.entries.push((key, value));
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        match self.entries.iter().position(|&(ref k, _)| k.as_slice() == key) {
+            Some(index) => {
+                let (_, value) = self.entries.swap_remove(index).unwrap();
+                Some(value)
+            },
+            None => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    pub fn iter(&self) -> Items<(String, String)> { self.entries.iter() }
+}
+
+/// A single zuffy message. On the wire it is made up of `ENVELOPE_FRAMES`
+/// frames:
+///
+///  1. header: protocol version (1 byte), kind (1 byte), request id (8 bytes,
+///     big-endian), remaining time budget in milliseconds (4 bytes,
+///     big-endian, zero if the request has no deadline);
+///  2. method name;
+///  3. metadata, encoded with `BinaryCodec` (empty if there is none);
+///  4. payload. For error replies: the error code (see `ErrorCode::to_u32`),
+///     description, details and up to `MAX_ERROR_CAUSES` causes, encoded
+///     with `BinaryCodec`. For credits: their number (4 bytes, big-endian).
+#[deriving(Clone, PartialEq, Show)]
+pub struct Envelope {
+    pub kind: Kind,
+    pub id: u64,
+    pub budget: Option<Duration>,
+    pub method: String,
+    pub metadata: Metadata,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn request(id: u64, budget: Option<Duration>, method: String,
+                   metadata: Metadata, payload: Vec<u8>) -> Envelope {
+        Envelope {
+            kind: Request,
+            id: id,
+            budget: budget,
+            method: method,
+            metadata: metadata,
+            payload: payload,
+        }
+    }
+
+    pub fn cancel(id: u64) -> Envelope {
+        Envelope::control(Cancel, id)
+    }
+
+    pub fn ready() -> Envelope {
+        Envelope::control(Ready, 0)
+    }
+
+    pub fn heartbeat() -> Envelope {
+        Envelope::control(Heartbeat, 0)
+    }
+
+    fn control(kind: Kind, id: u64) -> Envelope {
+        Envelope {
+            kind: kind,
+            id: id,
+            budget: None,
+            method: String::new(),
+            metadata: Metadata::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Builds the reply to request `id` carrying either a payload or an
+    /// error.
+    pub fn reply(id: u64, result: Result<Vec<u8>, Error>) -> Envelope {
+        let (kind, payload) = match result {
+            Ok(payload) => (Reply, payload),
+            Err(err) => (ErrorReply, encode_error(&err)),
+        };
+        Envelope {
+            kind: kind,
+            id: id,
+            budget: None,
+            method: String::new(),
+            metadata: Metadata::new(),
+            payload: payload,
+        }
+    }
+
+    /// One of the messages of stream `id`, in either direction.
+    pub fn stream_item(id: u64, payload: Vec<u8>) -> Envelope {
+        let mut envelope = Envelope::control(StreamItem, id);
+        envelope.payload = payload;
+        envelope
+    }
+
+    /// The end of a stream, or the error which ended it.
+    pub fn stream_end(id: u64, result: Result<(), Error>) -> Envelope {
+        match result {
+            Ok(()) => Envelope::control(StreamEnd, id),
+            Err(err) => Envelope::reply(id, Err(err)),
+        }
+    }
+
+    /// Grants the sender of stream `id` `credits` more messages.
+    pub fn credit(id: u64, credits: u32) -> Envelope {
+        let mut envelope = Envelope::control(Credit, id);
+        push_be(&mut envelope.payload, credits as u64, 4);
+        envelope
+    }
+
+    /// The number of credits granted by a credit.
+    pub fn credits(&self) -> Result<u32, Error> {
+        if self.kind != Credit || self.payload.len() != 4 {
+            return Err(Error::with_desc(NetworkError, "malformed credit"));
+        }
+        Ok(read_be(self.payload.as_slice()) as u32)
+    }
+
+    /// Returns the payload of a reply, or the error it carries.
+    pub fn into_result(self) -> Result<Vec<u8>, Error> {
+        match self.kind {
+            Reply => Ok(self.payload),
+            ErrorReply => Err(decode_error(self.payload.as_slice())),
+            kind => {
+                Err(Error::with_lazy_desc(NetworkError, proc() {
+                    format!("expected reply, got {}", kind)
+                }))
+            },
+        }
+    }
+
+    /// Appends the frames of this envelope to `route`.
+    pub fn encode(self, mut route: Frames) -> Frames {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.push(PROTOCOL_VERSION);
+        header.push(self.kind.to_byte());
+        push_be(&mut header, self.id, 8);
+        push_be(&mut header, encode_budget(self.budget) as u64, 4);
+        route.push(header);
+        route.push(self.method.into_bytes());
+        route.push(if self.metadata.is_empty() {
+            Vec::new()
+        } else {
+            codec::encode(&BinaryCodec, &self.metadata).unwrap()
+        });
+        route.push(self.payload);
+        route
+    }
+
+    /// Splits `frames` into the routing prefix and the envelope.
+    pub fn decode(mut frames: Frames) -> Result<(Frames, Envelope), Error> {
+        if frames.len() < ENVELOPE_FRAMES {
+            return Err(Error::with_desc(NetworkError, "missing envelope"));
+        }
+        let payload = frames.pop().unwrap();
+        let metadata = frames.pop().unwrap();
+        let method = frames.pop().unwrap();
+        let header = frames.pop().unwrap();
+
+        let (kind, id, budget) = try!(read_header(header.as_slice()));
+        let method = match String::from_utf8(method) {
+            Ok(method) => method,
+            Err(_) => return Err(Error::with_desc(
+                NetworkError, "method name is not valid utf-8")),
+        };
+        let metadata = if metadata.is_empty() {
+            Metadata::new()
+        } else {
+            try!(codec::decode(&BinaryCodec, metadata.as_slice()))
+        };
+
+        Ok((frames, Envelope {
+            kind: kind,
+            id: id,
+            budget: budget,
+            method: method,
+            metadata: metadata,
+            payload: payload,
+        }))
+    }
+
+    /// Reads the route, kind and id of an encoded message without decoding
+    /// the rest, e.g. to forward the message unchanged.
+    pub fn peek(frames: &[Vec<u8>]) -> Result<(Frames, Kind, u64), Error> {
+        if frames.len() < ENVELOPE_FRAMES {
+            return Err(Error::with_desc(NetworkError, "missing envelope"));
+        }
+        let route_len = frames.len() - ENVELOPE_FRAMES;
+        let (kind, id, _) = try!(read_header(frames[route_len].as_slice()));
+        Ok((frames[..route_len].to_vec(), kind, id))
+    }
+}
+
+fn read_header(header: &[u8]) -> Result<(Kind, u64, Option<Duration>), Error> {
+    if header.len() != HEADER_LEN {
+        return Err(Error::with_desc(NetworkError, "malformed header"));
+    }
+    if header[0] != PROTOCOL_VERSION {
+        let version = header[0];
+        return Err(Error::with_lazy_desc(NetworkError, proc() {
+            format!("unsupported protocol version {}", version)
+        }));
+    }
+    let kind = match Kind::from_byte(header[1]) {
+        Some(kind) => kind,
+        None => return Err(Error::with_desc(NetworkError,
+                                            "unknown message kind")),
+    };
+    let id = read_be(header[2..10]);
+    let budget = decode_budget(read_be(header[10..]) as u32);
+    Ok((kind, id, budget))
+}
+
+/// The payload of an error reply. The causes of the error follow it as a
+/// flat list, outermost first, so that decoding them can't recurse.
+#[deriving(Encodable, Decodable)]
+struct ErrorBody {
+    code: u32,
+    desc: String,
+    details: Vec<DetailBody>,
+    causes: Vec<CauseBody>,
+}
+
+#[deriving(Encodable, Decodable)]
+struct CauseBody {
+    code: u32,
+    desc: String,
+    details: Vec<DetailBody>,
+}
+
+#[deriving(Encodable, Decodable)]
+enum DetailBody {
+    RetryAfterMs(i64),
+    FieldViolationBody(String, String),
+    InfoBody(String, String),
+}
+
+/// How many causes of an error are sent along with it; deeper ones are
+/// dropped on either side.
+pub const MAX_ERROR_CAUSES: uint = 16;
+
+/// Forces the descriptions of `err` and its causes, which may be lazy.
+fn encode_error(err: &Error) -> Vec<u8> {
+    let mut causes = Vec::new();
+    let mut next = err.cause();
+    loop {
+        let cause = match next {
+            Some(cause) if causes.len() < MAX_ERROR_CAUSES => cause,
+            _ => break,
+        };
+        causes.push(CauseBody {
+            code: cause.code().to_u32(),
+            desc: cause.desc().to_string(),
+            details: detail_bodies(cause),
+        });
+        next = cause.cause();
+    }
+    let body = ErrorBody {
+        code: err.code().to_u32(),
+        desc: err.desc().to_string(),
+        details: detail_bodies(err),
+        causes: causes,
+    };
+    codec::encode(&BinaryCodec, &body).unwrap()
+}
+
+fn detail_bodies(err: &Error) -> Vec<DetailBody> {
+    err.details().iter().map(|detail| match *detail {
+        RetryAfter(delay) => RetryAfterMs(delay.num_milliseconds()),
+        FieldViolation(ref field, ref desc) => {
+            FieldViolationBody(field.clone(), desc.clone())
+        },
+        Info(ref key, ref value) => InfoBody(key.clone(), value.clone()),
+    }).collect()
+}
+
+fn decode_error(payload: &[u8]) -> Error {
+    let ErrorBody { code, desc, details, mut causes } =
+        match codec::decode(&BinaryCodec, payload) {
+            Ok(body) => body,
+            Err(err) => {
+                return Error::with_desc(NetworkError, "malformed error reply")
+                    .caused_by(err);
+            },
+        };
+    causes.truncate(MAX_ERROR_CAUSES);
+    // Chained from the innermost cause out.
+    let cause = causes.into_iter().rev().fold(None, |inner, cause| {
+        let CauseBody { code, desc, details } = cause;
+        let err = from_body(code, desc, details);
+        Some(match inner {
+            Some(inner) => err.caused_by(inner),
+            None => err,
+        })
+    });
+    let err = from_body(code, desc, details);
+    match cause {
+        Some(cause) => err.caused_by(cause),
+        None => err,
+    }
+}
+
+/// Codes this side doesn't know about, e.g. from a newer peer, come out as
+/// `InternalServerError`.
+fn from_body(code: u32, desc: String, details: Vec<DetailBody>) -> Error {
+    let code = ErrorCode::from_u32(code).unwrap_or(InternalServerError);
+    let mut err = Error::with_desc(code, desc);
+    for detail in details.into_iter() {
+        err = err.with_detail(match detail {
+            RetryAfterMs(ms) => RetryAfter(Duration::milliseconds(ms)),
+            FieldViolationBody(field, desc) => FieldViolation(field, desc),
+            InfoBody(key, value) => Info(key, value),
+        });
+    }
+    err
+}
+
+fn push_be(output: &mut Vec<u8>, value: u64, bytes: uint) {
+    for shift in range(0, bytes).rev() {
+        output.push((value >> (shift * 8)) as u8);
+    }
+}
+
+fn read_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |value, &byte| (value << 8) | byte as u64)
+}
+
+/// Budgets are rounded up to the next millisecond, so that only requests with
+/// no deadline are sent as zero.
+fn encode_budget(budget: Option<Duration>) -> u32 {
+    match budget {
+        Some(budget) => {
+            let ns = budget.num_nanoseconds().unwrap_or(i64::MAX);
+            let ms = (cmp::max(ns, 1) as u64 + 999_999) / 1_000_000;
+            cmp::min(ms, u32::MAX as u64) as u32
+        },
+        None => 0,
+    }
+}
+
+fn decode_budget(ms: u32) -> Option<Duration> {
+    if ms == 0 { None } else { Some(Duration::milliseconds(ms as i64)) }
+}
+
+pub fn send_frames(socket: &mut zmq::Socket, frames: &[Vec<u8>])
+        -> Result<(), Error> {
+    let last = frames.len() - 1;
+    for (index, frame) in frames.iter().enumerate() {
+        let flags = if index == last { 0 } else { zmq::SNDMORE };
+        try!(socket.send(frame.as_slice(), flags).map_err(Error::from_zmq));
+    }
+    Ok(())
+}
+
+/// Like `send_frames`, but returns `Ok(false)` instead of blocking if the
+/// socket cannot accept the message.
+///
+/// Messages routed to a peer which a `ROUTER_MANDATORY` socket does not know
+/// of (any more) are dropped, as a plain ROUTER would.
+pub fn try_send_frames(socket: &mut zmq::Socket, frames: &[Vec<u8>])
+        -> Result<bool, Error> {
+    // Once the first frame is accepted, so are the rest of the message.
+    let more = if frames.len() > 1 { zmq::SNDMORE } else { 0 };
+    match socket.send(frames[0].as_slice(), zmq::DONTWAIT | more) {
+        Ok(()) => {},
+        Err(zmq::EAGAIN) => return Ok(false),
+        Err(zmq::EHOSTUNREACH) => {
+            debug!("wire: dropping message to unreachable peer");
+            return Ok(true);
+        },
+        Err(err) => return Err(Error::from_zmq(err)),
+    }
+    if more != 0 {
+        try!(send_frames(socket, frames[1..]));
+    }
+    Ok(true)
+}
+
+/// Messages waiting for a socket to become writable. Clones share the same
+/// queue, so one can be handed to `Reactor::set_send_queue` while the owner of
+/// the socket pushes to and flushes another.
+#[deriving(Clone)]
+pub struct SendQueue {
+    messages: Rc<RefCell<RingBuf<Frames>>>,
+}
+
+impl SendQueue {
+    pub fn new() -> SendQueue {
+        SendQueue { messages: Rc::new(RefCell::new(RingBuf::new())) }
+    }
+
+    pub fn len(&self) -> uint { self.messages.borrow().len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn push(&self, frames: Frames) {
+        self.messages.borrow_mut().push_back(frames);
+    }
+
+    /// Calls `f` with every queued message, oldest first.
+    pub fn each(&self, f: |&[Vec<u8>]|) {
+        for frames in self.messages.borrow().iter() {
+            f(frames.as_slice());
+        }
+    }
+
+    /// Sends queued messages in order, without blocking, until the queue is
+    /// empty or the socket stops accepting them.
+    pub fn flush(&self, socket: &mut zmq::Socket) -> Result<(), Error> {
+        let mut messages = self.messages.borrow_mut();
+        while !messages.is_empty() {
+            let sent = try!(try_send_frames(
+                socket, messages.front().unwrap().as_slice()));
+            if !sent { break; }
+            messages.pop_front();
+        }
+        Ok(())
+    }
+}
+
+pub fn recv_frames(socket: &mut zmq::Socket, flags: int)
+        -> Result<Frames, Error> {
+    let mut frames = Vec::new();
+    loop {
+        frames.push(try!(socket.recv_bytes(flags).map_err(Error::from_zmq)));
+        if !try!(socket.get_rcvmore().map_err(Error::from_zmq)) {
+            return Ok(frames);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Envelope, Metadata, Cancel, Credit, ErrorReply, Reply};
+    use super::{SendQueue, StreamEnd, StreamItem, MAX_ERROR_CAUSES};
+    use super::PROTOCOL_VERSION;
+    use error::{Error, Application, InternalServerError, NetworkError};
+    use error::{InvalidArgument, NotFound, FieldViolation, RetryAfter};
+    use std::time::Duration;
+    use zmq;
+
+    fn request() -> Envelope {
+        let mut metadata = Metadata::new();
+        metadata.set("user".to_string(), "cristi".to_string());
+        Envelope::request(0x0102030405060708, Some(Duration::seconds(2)),
+                          "add".to_string(), metadata, vec![1, 2, 3])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frames = request().encode(vec![b"peer".to_vec()]);
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[1].as_slice(),
+                   [PROTOCOL_VERSION, 0, 1, 2, 3, 4, 5, 6, 7, 8,
+                    0, 0, 0x07, 0xd0].as_slice());
+
+        let (route, envelope) = Envelope::decode(frames).ok().unwrap();
+        assert_eq!(route, vec![b"peer".to_vec()]);
+        assert_eq!(envelope.budget, Some(Duration::seconds(2)));
+        assert_eq!(envelope.payload, request().payload);
+        assert_eq!(envelope.metadata.get("user"), Some("cristi"));
+    }
+
+    #[test]
+    fn test_reply() {
+        let reply = Envelope::reply(7, Ok(vec![4]));
+        assert_eq!(reply.kind, Reply);
+        assert_eq!(reply.into_result().ok(), Some(vec![4]));
+
+        let reply = Envelope::reply(
+            7, Err(Error::with_desc(InternalServerError, "oops")));
+        assert_eq!(reply.kind, ErrorReply);
+        let (_, reply) =
+            Envelope::decode(reply.encode(Vec::new())).ok().unwrap();
+        assert_eq!(reply.id, 7);
+        let err = reply.into_result().err().unwrap();
+        assert_eq!(err.code(), InternalServerError);
+        assert_eq!(err.desc(), "oops");
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let key = "k".to_string();
+        let reply = Envelope::reply(1, Err(Error::with_lazy_desc(
+            NotFound, proc() format!("no key '{}'", key))));
+        let err = reply.into_result().err().unwrap();
+        assert_eq!(err.code(), NotFound);
+        assert_eq!(err.desc(), "no key 'k'");
+
+        let violation = FieldViolation("a".to_string(), "negative".to_string());
+        let reply = Envelope::reply(1, Err(
+            Error::with_desc(InvalidArgument, "bad request")
+                .with_detail(violation.clone())
+                .caused_by(Error::new(NotFound)
+                           .with_detail(RetryAfter(Duration::seconds(2))))));
+        let (_, reply) =
+            Envelope::decode(reply.encode(Vec::new())).ok().unwrap();
+        let err = reply.into_result().err().unwrap();
+        assert_eq!(err.code(), InvalidArgument);
+        assert_eq!(err.details(), [violation].as_slice());
+        let cause = err.cause().unwrap();
+        assert_eq!(cause.code(), NotFound);
+        assert_eq!(cause.retry_after(), Some(Duration::seconds(2)));
+        assert!(cause.cause().is_none());
+
+        let reply = Envelope::reply(
+            1, Err(Error::with_desc(Application(3), "custom")));
+        assert_eq!(reply.into_result().err().unwrap().code(), Application(3));
+
+        // Unknown codes, and garbage.
+        let mut reply = Envelope::reply(1, Err(Error::new(NotFound)));
+        reply.payload[0] = 2;
+        assert_eq!(reply.clone().into_result().err().unwrap().code(),
+                   InternalServerError);
+        reply.payload = vec![0xff];
+        assert_eq!(reply.into_result().err().unwrap().code(), NetworkError);
+    }
+
+    #[test]
+    fn test_error_chain_depth() {
+        let err = range(0u, MAX_ERROR_CAUSES * 2).fold(
+            Error::new(NotFound),
+            |cause, _| Error::new(InvalidArgument).caused_by(cause));
+        let reply = Envelope::reply(1, Err(err));
+        let err = reply.into_result().err().unwrap();
+        let mut depth = 0u;
+        let mut next = err.cause();
+        loop {
+            next = match next {
+                Some(cause) => cause.cause(),
+                None => break,
+            };
+            depth += 1;
+        }
+        assert_eq!(depth, MAX_ERROR_CAUSES);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Envelope::cancel(9);
+        let (route, cancel) = Envelope::decode(
+            cancel.encode(vec![b"peer".to_vec()])).ok().unwrap();
+        assert_eq!(route, vec![b"peer".to_vec()]);
+        assert_eq!(cancel.kind, Cancel);
+        assert_eq!(cancel.id, 9);
+        assert_eq!(cancel.into_result().err().unwrap().code(), NetworkError);
+    }
+
+    #[test]
+    fn test_peek() {
+        let frames = Envelope::reply(7, Ok(vec![4]))
+            .encode(vec![b"peer".to_vec()]);
+        let (route, kind, id) = Envelope::peek(frames.as_slice()).ok().unwrap();
+        assert_eq!(route, vec![b"peer".to_vec()]);
+        assert_eq!(kind, Reply);
+        assert_eq!(id, 7);
+        assert!(Envelope::peek(frames[2..]).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let (_, item) = Envelope::decode(
+            Envelope::stream_item(3, vec![1]).encode(Vec::new())).ok().unwrap();
+        assert_eq!(item.kind, StreamItem);
+        assert_eq!(item.id, 3);
+        assert_eq!(item.payload, vec![1]);
+        assert_eq!(Envelope::stream_end(3, Ok(())).kind, StreamEnd);
+        let end = Envelope::stream_end(3, Err(Error::new(NotFound)));
+        assert_eq!(end.kind, ErrorReply);
+        assert_eq!(end.into_result().err().unwrap().code(), NotFound);
+
+        let (_, credit) = Envelope::decode(
+            Envelope::credit(3, 70000).encode(Vec::new())).ok().unwrap();
+        assert_eq!(credit.kind, Credit);
+        assert_eq!(credit.credits().ok(), Some(70000));
+        assert!(item.credits().is_err());
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let mut frames = request().encode(Vec::new());
+        frames[0][0] = PROTOCOL_VERSION + 1;
+        let err = Envelope::decode(frames).err().unwrap();
+        assert_eq!(err.code(), NetworkError);
+    }
+
+    #[test]
+    fn test_missing_frames() {
+        let mut frames = request().encode(Vec::new());
+        frames.pop();
+        assert!(Envelope::decode(frames).is_err());
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut request = request();
+        request.budget = None;
+        let (_, decoded) =
+            Envelope::decode(request.clone().encode(Vec::new())).ok().unwrap();
+        assert_eq!(decoded, request);
+
+        request.budget = Some(Duration::zero());
+        let (_, decoded) =
+            Envelope::decode(request.clone().encode(Vec::new())).ok().unwrap();
+        assert_eq!(decoded.budget, Some(Duration::milliseconds(1)));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut metadata = Metadata::new();
+        assert!(metadata.is_empty());
+        metadata.set("a".to_string(), "1".to_string());
+        metadata.set("b".to_string(), "2".to_string());
+        metadata.set("a".to_string(), "3".to_string());
+        assert_eq!(metadata.get("a"), Some("3"));
+        assert_eq!(metadata.remove("a"), Some("3".to_string()));
+        assert_eq!(metadata.get("a"), None);
+        assert_eq!(metadata.get("b"), Some("2"));
+    }
+
+    #[test]
+    fn test_send_queue_drops_unroutable() {
+        let mut ctx = zmq::Context::new();
+        let mut socket = ctx.socket(zmq::ROUTER).unwrap();
+        socket.set_router_mandatory(true).unwrap();
+        let queue = SendQueue::new();
+        queue.push(vec![b"nobody".to_vec(), b"reply".to_vec()]);
+        queue.flush(&mut socket).unwrap();
+        assert!(queue.is_empty());
+    }
+}
@@ -2,18 +2,36 @@
 #![feature(phase)]
 #![feature(overloaded_calls)]
 #![feature(slicing_syntax)]
+#![feature(unsafe_destructor)]
+#![feature(macro_rules, globs)]
 
 #[phase(plugin, link)]
 extern crate log;
+extern crate serialize;
 extern crate time;
 extern crate zmq;
 
 
+pub mod service;
+
+pub mod broker;
+pub mod circuit;
+pub mod client;
+pub mod codec;
+pub mod deadline;
 pub mod error;
+pub mod executor;
+pub mod flow;
 pub mod future;
 pub mod lazy;
+pub mod liveness;
 pub mod movecell;
 pub mod reactor;
+pub mod retry;
+pub mod sendfuture;
+pub mod server;
+pub mod stream;
+pub mod wire;
 
 type Mapper<I, O> = proc(I):'static -> O;
 
@@ -105,32 +123,160 @@ struct MapProducer<I, O, J, W> {
 
 
 
+zuffy_service! {
+    service Echo {
+        fn echo(String) -> String;
+    }
+}
+
+#[deriving(Clone)]
+struct EchoServer;
+impl Echo::Server for EchoServer {
+    fn echo(&mut self, _ctx: &server::Context, request: String)
+            -> Result<String, error::Error> {
+        info!("server: received '{}'", request);
+        Ok(request)
+    }
+}
+
 #[cfg(not(test))]
 fn main() {
-    use zmq;
+    let args = std::os::args();
+    let result = if args.len() > 1 && args[1].as_slice() == "broker" {
+        let frontend =
+            if args.len() > 2 { args[2].as_slice() } else { "tcp://*:5555" };
+        let backend =
+            if args.len() > 3 { args[3].as_slice() } else { "tcp://*:5556" };
+        run_broker(frontend, backend)
+    } else {
+        run_echo_demo()
+    };
+    match result {
+        Ok(()) => {},
+        Err(err) => {
+            error!("{}", err);
+            std::os::set_exit_status(1);
+        }
+    }
+}
+
+/// `zuffy broker [frontend] [backend]`: serves clients on `frontend` with the
+/// workers connected to `backend`.
+#[cfg(not(test))]
+fn run_broker(frontend_address: &str, backend_address: &str)
+        -> Result<(), error::Error> {
+    use broker::Broker;
+    use error::Error;
     use reactor::Reactor;
+    use std::cell::RefCell;
+    use zmq;
+
+    let mut ctx = zmq::Context::new();
+    let mut frontend = try!(ctx.socket(zmq::ROUTER).map_err(Error::from_zmq));
+    try!(frontend.bind(frontend_address).map_err(Error::from_zmq));
+    let mut backend = try!(ctx.socket(zmq::ROUTER).map_err(Error::from_zmq));
+    try!(backend.bind(backend_address).map_err(Error::from_zmq));
+    info!("broker: clients on {}, workers on {}",
+          frontend_address, backend_address);
+
+    let broker = RefCell::new(Broker::new(frontend, backend));
+    let mut reactor = Reactor::new();
+    reactor.push_item(
+        box |&: events: i16| broker.borrow().frontend_poll_item(events),
+        box |&mut:| {
+            match broker.borrow_mut().process_frontend() {
+                Ok(()) => {},
+                Err(err) => error!("broker: frontend: {}", err),
+            }
+        });
+    reactor.push_item(
+        box |&: events: i16| broker.borrow().backend_poll_item(events),
+        box |&mut:| {
+            match broker.borrow_mut().process_backend() {
+                Ok(()) => {},
+                Err(err) => error!("broker: backend: {}", err),
+            }
+        });
+    Broker::register_expiry(&broker, &mut reactor);
+    let interval = broker.borrow().liveness().interval();
+    reactor.schedule_repeating(interval, box |&mut:| {
+        match broker.borrow_mut().heartbeat() {
+            Ok(()) => {},
+            Err(err) => error!("broker: heartbeat: {}", err),
+        }
+    });
+    reactor.run()
+}
+
+#[cfg(not(test))]
+fn run_echo_demo() -> Result<(), error::Error> {
+    use codec::JsonCodec;
+    use error::Error;
+    use reactor::{mod, Reactor};
+    use executor::ThreadPool;
+    use server::Endpoint;
+    use std::cell::RefCell;
+    use std::sync::Future as StdFuture;
+    use std::time::Duration;
+    use zmq;
 
     let mut ctx = zmq::Context::new();
-    let mut server = ctx.socket(zmq::DEALER).unwrap();
-    server.bind("tcp://*:8080").unwrap();
+    let mut socket = try!(ctx.socket(zmq::ROUTER).map_err(Error::from_zmq));
+    try!(socket.bind("tcp://*:8080").map_err(Error::from_zmq));
+    let (mut stop, control) = try!(reactor::control(&mut ctx));
+    let pool = try!(ThreadPool::new(
+        &mut ctx, 4, Echo::Dispatcher::new(EchoServer, JsonCodec)));
 
     let sf = StdFuture::spawn(proc() {
+        let endpoint = RefCell::new(Endpoint::with_executor(socket, pool));
         let mut reactor = Reactor::new();
+        reactor.push_control(control);
+        let token = reactor.push_acceptor(
+            box |&: events: i16| endpoint.borrow().poll_item(events),
+            box |&mut: revents: i16| {
+                match endpoint.borrow_mut().handle_events(revents) {
+                    Ok(()) => {},
+                    Err(err) => error!("server: {}", err),
+                }
+            });
+        reactor.set_send_queue(token, endpoint.borrow().send_queue());
         reactor.push_item(
-            server.as_poll_item(zmq::POLLIN),
-            || {
-                info!("server: received '{}'", server.recv_str(0).unwrap());
+            box |&: events: i16| {
+                endpoint.borrow().executor_poll_item(events)
+                    .expect("Thread pools have replies to poll.")
+            },
+            box |&mut:| {
+                match endpoint.borrow_mut().process_reply() {
+                    Ok(()) => {},
+                    Err(err) => error!("server: {}", err),
+                }
             });
-        reactor.run();
+        reactor.watch_in_flight(box |&:| endpoint.borrow().in_flight());
+        reactor.run()
     });
 
-    let mut client = ctx.socket(zmq::DEALER).unwrap();
-    client.connect("tcp://127.0.0.1:8080").unwrap();
-    client.send_str("message1", 0).unwrap();
-    client.send_str("message2", 0).unwrap();
-    client.send_str("message3", 0).unwrap();
-    client.send_str("message4", 0).unwrap();
-    client.send_str("message5", 0).unwrap();
+    // The server is stopped even if the client fails.
+    let result = run_echo_client(&mut ctx);
+    try!(stop.drain(Duration::seconds(1)));
+    try!(sf.unwrap());
+    result
+}
 
-    sf.unwrap();
+#[cfg(not(test))]
+fn run_echo_client(ctx: &mut zmq::Context) -> Result<(), error::Error> {
+    use client::Channel;
+    use codec::JsonCodec;
+    use error::Error;
+    use zmq;
+
+    let mut socket = try!(ctx.socket(zmq::DEALER).map_err(Error::from_zmq));
+    try!(socket.connect("tcp://127.0.0.1:8080").map_err(Error::from_zmq));
+    let mut client = Echo::Client::new(Channel::new(socket, JsonCodec));
+    for i in range(1u, 6) {
+        match client.echo(format!("message{}", i)).sync() {
+            Ok(reply) => info!("client: received '{}'", reply),
+            Err(err) => error!("client: {}", err),
+        }
+    }
+    Ok(())
 }